@@ -8,7 +8,7 @@ use std::collections::HashMap;
 use crate::*;
 
 /// Contains a time as u64 seconds.
-#[derive(Default, Debug, Eq, PartialEq)]
+#[derive(Clone, Default, Debug, Eq, PartialEq)]
 pub struct Time(pub u64);
 
 /// Contains a path on the filesystem.
@@ -51,6 +51,115 @@ decl_fixed_typemap!(
     }
 );
 
+/// Settings which are used to build other entries.
+#[derive(Debug)]
+pub struct Settings {
+    pub scale: u64,
+}
+
+/// A value derived from [Settings] when the map is constructed.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Scaled(pub u64);
+
+decl_fixed_typemap!(
+    /// Initializers can refer to any named entry declared before them.
+    pub struct ExampleMapDependent {
+        pub settings: Settings = Settings { scale: 3 },
+        _: Scaled = Scaled(settings.scale * 2),
+    }
+);
+
+/// A port number, which is parsed when the map is built.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Port(pub u16);
+
+decl_fixed_typemap!(
+    /// A typemap whose initializers can fail.  This generates `try_new` instead of `new`.
+    #[fixed_typemap(fallible(error = "std::num::ParseIntError"))]
+    pub struct ExampleMapFallible {
+        _: Port = Port("8080".parse()?),
+        _: Time,
+    }
+);
+
+decl_fixed_typemap!(
+    /// A typemap whose `new` is a `const fn`, so that it can be used to initialize statics.
+    #[fixed_typemap(const_new)]
+    pub struct ExampleMapConst {
+        pub time: Time = Time(60),
+        _: Filesystem = Filesystem { path: "/tmp" },
+    }
+);
+
+/// Built at compile time.
+pub static EXAMPLE_CONST_MAP: ExampleMapConst = ExampleMapConst::new();
+
+/// A large table, which we don't want to copy around inline.
+pub struct BigTable(pub [u64; 4096]);
+
+impl Default for BigTable {
+    fn default() -> Self {
+        BigTable([0; 4096])
+    }
+}
+
+decl_fixed_typemap!(
+    /// A typemap which keeps a large entry behind a box, so that the map itself stays small.
+    #[fixed_typemap(dynamic)]
+    pub struct ExampleMapBoxed {
+        _: Time,
+        #[fixed_typemap(boxed)]
+        _: BigTable,
+    }
+);
+
+/// Marks the maximum number of players, which is stored as a `u32`.
+pub struct MaxPlayers;
+
+/// Marks the minimum number of players, which is also a `u32`.
+pub struct MinPlayers;
+
+impl TypemapKey for MaxPlayers {
+    type Value = u32;
+}
+
+decl_fixed_typemap!(
+    /// Marker keys let a map hold more than one value of the same type.
+    #[fixed_typemap(dynamic)]
+    pub struct ExampleMapMarkers {
+        _: MaxPlayers => u32 = 16,
+        _: MinPlayers => u32 = 2,
+    }
+);
+
+decl_fixed_typemap!(
+    /// A typemap whose entries can be set from a settings file, with `apply_config`.
+    #[fixed_typemap(config)]
+    pub struct ExampleMapConfig {
+        pub volume: f32 = 0.5,
+        #[fixed_typemap(config = "players.max")]
+        _: MaxPlayers => u32 = 16,
+        #[fixed_typemap(lazy, config = "motd")]
+        _: String = String::from("Welcome!"),
+        // Named entries can be configured unless they opt out, and `_` entries only if they opt in.
+        #[fixed_typemap(config = false)]
+        pub metrics: Metrics = build_initial_metrics(),
+        _: Time,
+    }
+);
+
+/// A map built from an ordinary struct with the derive, rather than with `decl_fixed_typemap!`.
+#[derive(FixedTypemap)]
+pub struct ExampleMapDerived {
+    pub time: Time,
+    #[fixed_typemap(default = "Scaled(3)")]
+    pub scaled: Scaled,
+    #[fixed_typemap(marker = "MaxPlayers", default = "16")]
+    pub max_players: u32,
+    #[fixed_typemap(dynamic)]
+    dynamic: DynamicSection<Self>,
+}
+
 /// For demonstration purposes, a trait which represents things containing integral ids.
 pub trait IntegralId {
     fn get_id(&self) -> u64;
@@ -59,19 +168,19 @@ pub trait IntegralId {
 
 #[derive(Default, derive_more::Display)]
 #[display(fmt = "id1={}", _0)]
-struct IdContainer1(u64);
+pub struct IdContainer1(pub u64);
 
 #[derive(Default, derive_more::Display)]
 #[display(fmt = "id2={}", _0)]
-struct IdContainer2(u64);
+pub struct IdContainer2(pub u64);
 
 #[derive(Default, derive_more::Display)]
 #[display(fmt = "id3={}", _0)]
-struct IdContainer3(u64);
+pub struct IdContainer3(pub u64);
 
 #[derive(Default, derive_more::Display)]
 #[display(fmt = "id4={}", _0)]
-struct IdContainer4(u64);
+pub struct IdContainer4(pub u64);
 
 macro_rules! impl_integral_id {
     ($t: ty) => {
@@ -105,11 +214,67 @@ decl_fixed_typemap! {
     }
 }
 
+decl_fixed_typemap! {
+    /// A typemap with a lazy entry, which is only built the first time it is accessed.
+    ///
+    /// This map asks trait iteration to skip lazy entries which haven't been built yet.
+    #[fixed_typemap(iterable_traits(IntegralId = "iter_integral_id"), lazy_iteration = "skip")]
+    pub struct ExampleMapLazy {
+        _: IdContainer1,
+        #[fixed_typemap(lazy)]
+        _: IdContainer2 = IdContainer2(2),
+    }
+}
+
+/// Generated code doesn't rely on the prelude, or on names like `Box` and `Default` meaning what they usually do.
+#[cfg(test)]
+#[no_implicit_prelude]
+mod no_prelude {
+    #[allow(dead_code)]
+    struct Box;
+
+    #[allow(dead_code)]
+    trait Default {}
+
+    ::fixed_typemap::decl_fixed_typemap! {
+        #[fixed_typemap(dynamic, iterable_traits(super::IntegralId = "iter_integral_id"))]
+        pub struct NoPreludeMap {
+            pub first: super::IdContainer1,
+            #[fixed_typemap(lazy, boxed)]
+            _: super::IdContainer2 = super::IdContainer2(2),
+            #[cfg(test)]
+            _: super::MaxPlayers => super::IdContainer3 = super::IdContainer3(3),
+        }
+    }
+
+    ::fixed_typemap::decl_fixed_typemap! {
+        #[fixed_typemap(dynamic, diff, config, persist(version = 1))]
+        pub struct NoPreludeReplicated {
+            #[fixed_typemap(key = "volume")]
+            pub volume: f32 = 0.5,
+            #[fixed_typemap(lazy, boxed, key = "motd", config = "motd")]
+            _: ::std::string::String,
+        }
+    }
+
+    // Going through `crate`, as a facade crate re-exporting fixed_typemap would.
+    #[derive(crate::FixedTypemap)]
+    #[fixed_typemap(
+        crate = "crate",
+        iterable_traits(super::IntegralId = "iter_integral_id")
+    )]
+    pub struct NoPreludeDerived {
+        pub first: super::IdContainer1,
+        #[fixed_typemap(dynamic)]
+        dynamic: crate::DynamicSection<Self>,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use crate::IterableAs;
+    use crate::{GettableAs, IterableAs};
 
     #[test]
     fn test_infallible_getters() {
@@ -135,6 +300,1054 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_dependent_initializers() {
+        let map = ExampleMapDependent::new();
+
+        assert_eq!(map.settings.scale, 3);
+        assert_eq!(map.get_infallible::<Scaled>(), &Scaled(6));
+    }
+
+    fn scale() -> u64 {
+        4
+    }
+
+    decl_fixed_typemap! {
+        struct ExampleMapSelfNamed {
+            scale: u64 = scale(),
+            doubled: u32 = scale as u32 * 2,
+        }
+    }
+
+    #[test]
+    fn test_initializer_using_own_name() {
+        let map = ExampleMapSelfNamed::new();
+        assert_eq!(map.scale, 4);
+        assert_eq!(map.doubled, 8);
+    }
+
+    decl_fixed_typemap! {
+        struct ExampleMapLaterNames {
+            doubled: u64 = scale() * 2,
+            scale: u32 = 1,
+        }
+    }
+
+    #[test]
+    fn test_function_named_like_later_entry() {
+        // `scale()` calls the function, so it isn't a reference to the entry declared after it.
+        let map = ExampleMapLaterNames::new();
+        assert_eq!(map.doubled, 8);
+        assert_eq!(map.scale, 1);
+    }
+
+    thread_local! {
+        static DROPPED: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+        static INPUT: std::cell::Cell<&'static str> = const { std::cell::Cell::new("") };
+    }
+
+    #[derive(Default)]
+    struct DropTracker;
+
+    impl Drop for DropTracker {
+        fn drop(&mut self) {
+            DROPPED.with(|d| d.set(d.get() + 1));
+        }
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(fallible(error = "std::num::ParseIntError"))]
+        struct TestFallible {
+            _: DropTracker,
+            parsed: u64 = INPUT.with(|i| i.get()).parse()?,
+        }
+    }
+
+    #[test]
+    fn test_try_new() {
+        let map = ExampleMapFallible::try_new().unwrap();
+        assert_eq!(map.get_infallible::<Port>(), &Port(8080));
+
+        INPUT.with(|i| i.set("5"));
+        assert_eq!(TestFallible::try_new().unwrap().parsed, 5);
+
+        // The tracker was built before parsing failed, so it must have been dropped.
+        DROPPED.with(|d| d.set(0));
+        INPUT.with(|i| i.set("not a number"));
+        assert!(TestFallible::try_new().is_err());
+        assert_eq!(DROPPED.with(|d| d.get()), 1);
+    }
+
+    thread_local! {
+        static LAZY_INITS: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+    }
+
+    fn count_lazy_init() -> IdContainer3 {
+        LAZY_INITS.with(|i| i.set(i.get() + 1));
+        IdContainer3(3)
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(iterable_traits(IntegralId = "iter_integral_id"))]
+        struct TestLazy {
+            _: IdContainer1,
+            #[fixed_typemap(lazy)]
+            _: IdContainer3 = count_lazy_init(),
+        }
+    }
+
+    #[test]
+    fn test_lazy() {
+        LAZY_INITS.with(|i| i.set(0));
+        let mut map = TestLazy::new();
+        assert_eq!(LAZY_INITS.with(|i| i.get()), 0);
+
+        assert_eq!(map.get_infallible::<IdContainer3>().0, 3);
+        map.get_mut::<IdContainer3>().unwrap().0 = 4;
+        assert_eq!(map.get::<IdContainer3>().unwrap().0, 4);
+        assert_eq!(LAZY_INITS.with(|i| i.get()), 1);
+
+        // By default, iteration initializes lazy entries.
+        let mut map = TestLazy::new();
+        let mut ids = map
+            .iter_integral_id()
+            .map(|x| x.get_id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 3]);
+        assert_eq!(LAZY_INITS.with(|i| i.get()), 2);
+        for i in map.iter_integral_id_mut() {
+            i.set_id(i.get_id() + 1);
+        }
+        assert_eq!(map.get_infallible::<IdContainer3>().0, 4);
+    }
+
+    #[test]
+    fn test_lazy_writes() {
+        LAZY_INITS.with(|i| i.set(0));
+        let mut map = TestLazy::new();
+
+        // Writing or checking for a lazy entry doesn't build it first.
+        assert!(map.contains::<IdContainer3>());
+        assert!(map.insert(IdContainer3(7)).unwrap().is_none());
+        assert_eq!(
+            map.insert_infallible::<IdContainer3>(IdContainer3(8))
+                .unwrap()
+                .0,
+            7
+        );
+        assert_eq!(map.get_infallible::<IdContainer3>().0, 8);
+        assert_eq!(LAZY_INITS.with(|i| i.get()), 0);
+    }
+
+    #[test]
+    fn test_lazy_skipped_by_iteration() {
+        let mut map = ExampleMapLazy::new();
+        assert_eq!(
+            map.iter_integral_id()
+                .map(|x| x.get_id())
+                .collect::<Vec<_>>(),
+            vec![0]
+        );
+        assert_eq!(<dyn IntegralId>::iter_mut_as(&mut map).count(), 1);
+
+        map.get_infallible_mut::<IdContainer2>().0 = 5;
+        let mut ids = map
+            .iter_integral_id()
+            .map(|x| x.get_id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 5]);
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(const_new)]
+        struct TestConstLazy {
+            limit: u32 = 8,
+            #[fixed_typemap(lazy, boxed)]
+            _: Metrics = build_initial_metrics(),
+        }
+    }
+
+    static CONST_LAZY: TestConstLazy = TestConstLazy::new();
+
+    #[test]
+    fn test_const_new() {
+        const TIME: Time = ExampleMapConst::new().time;
+        assert_eq!(TIME, Time(60));
+        assert_eq!(
+            EXAMPLE_CONST_MAP.get_infallible::<Filesystem>().path,
+            "/tmp"
+        );
+
+        // Lazy entries can be in a static, which is shared between threads, and are built by whichever gets there first.
+        assert_eq!(CONST_LAZY.limit, 8);
+        let successes =
+            std::thread::spawn(|| CONST_LAZY.get_infallible::<Metrics>().0["successes"])
+                .join()
+                .unwrap();
+        assert_eq!(successes, 5);
+        assert_eq!(CONST_LAZY.get_infallible::<Metrics>().0["failures"], 10);
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(iterable_traits(IntegralId = "iter_integral_id"))]
+        struct TestBoxed {
+            #[fixed_typemap(boxed)]
+            _: IdContainer1 = IdContainer1(1),
+            #[fixed_typemap(boxed, lazy)]
+            _: IdContainer2 = IdContainer2(2),
+        }
+    }
+
+    #[test]
+    fn test_boxed() {
+        assert!(std::mem::size_of::<ExampleMapBoxed>() < std::mem::size_of::<BigTable>());
+
+        let mut map = ExampleMapBoxed::new();
+        map.get_infallible_mut::<BigTable>().0[5] = 5;
+        assert_eq!(map.get::<BigTable>().unwrap().0[5], 5);
+        let old = map.insert(BigTable::default()).unwrap().unwrap();
+        assert_eq!(old.0[5], 5);
+        assert_eq!(map.get_infallible::<BigTable>().0[5], 0);
+
+        let mut map = TestBoxed::new();
+        for i in map.iter_integral_id_mut() {
+            i.set_id(i.get_id() * 10);
+        }
+        assert_eq!(map.get_infallible::<IdContainer1>().0, 10);
+        assert_eq!(map.get_infallible::<IdContainer2>().0, 20);
+    }
+
+    struct RoundSeconds;
+
+    impl TypemapKey for RoundSeconds {
+        type Value = u64;
+    }
+
+    #[test]
+    fn test_marker_keys() {
+        let mut map = ExampleMapMarkers::new();
+
+        assert_eq!(*map.get_infallible::<MaxPlayers>(), 16);
+        assert_eq!(*map.get_infallible::<MinPlayers>(), 2);
+        assert_eq!(map.insert_infallible::<MinPlayers>(3), Some(2));
+        *map.get_infallible_mut::<MaxPlayers>() = 32;
+
+        // The stored type isn't a key, and neither is the marker on its own.
+        assert!(map.get::<u32>().is_none());
+        assert!(map.get::<MaxPlayers>().is_none());
+        assert_eq!(map.get_keyed::<MaxPlayers>(), Some(&32));
+        assert_eq!(map.insert_keyed::<MaxPlayers>(8), Ok(Some(32)));
+        assert_eq!(*map.get_infallible::<MaxPlayers>(), 8);
+
+        // Markers work in the dynamic section too.
+        assert_eq!(map.insert_keyed::<RoundSeconds>(60), Ok(None));
+        *map.get_keyed_mut::<RoundSeconds>().unwrap() += 1;
+        assert_eq!(map.get_keyed::<RoundSeconds>(), Some(&61));
+        assert!(map.get::<RoundSeconds>().is_none());
+        assert!(map.insert(RoundSeconds).is_err());
+
+        // Nor can a marker used by a fixed entry be stored as a value of its own in the dynamic section.
+        let len = map.len();
+        assert!(map.insert(MaxPlayers).is_err());
+        assert!(map.insert_boxed(Box::new(MaxPlayers)).is_err());
+        assert_eq!(map.len(), len);
+    }
+
+    /// Stands in for library code which accepts any map.
+    fn bump_time<M: Typemap>(map: &mut M) -> bool {
+        match map.get_mut::<Time>() {
+            Some(t) => {
+                t.0 += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn test_typemap_trait() {
+        let mut fixed = ExampleMapFixed::new();
+        let mut dynamic = ExampleMapDynamic::new();
+        assert!(bump_time(&mut fixed));
+        assert!(bump_time(&mut dynamic));
+        assert_eq!(fixed.get_infallible::<Time>().0, 1);
+        assert!(!bump_time(&mut ExampleMapMarkers::new()));
+
+        assert_eq!(Typemap::len(&fixed), 4);
+        assert!(Typemap::contains::<Metrics>(&fixed));
+        assert!(Typemap::insert(&mut fixed, 5u64).is_err());
+
+        assert!(!dynamic.contains::<u64>());
+        assert_eq!(Typemap::insert(&mut dynamic, 5u64), Ok(None));
+        assert!(dynamic.contains::<u64>());
+        assert_eq!(dynamic.len(), 5);
+
+        // Maps which iterate by traits can't add new dynamic entries this way, but can still replace fixed ones.
+        let mut iterable = IterationExampleMap::new();
+        assert!(Typemap::insert(&mut iterable, IdContainer3(3)).is_err());
+        assert!(Typemap::insert(&mut iterable, IdContainer1(1))
+            .unwrap()
+            .is_some());
+        assert!(!Typemap::is_empty(&iterable));
+    }
+
+    #[test]
+    fn test_dyn_typemap() {
+        let mut maps: Vec<Box<dyn DynTypemap>> = vec![
+            Box::new(ExampleMapFixed::new()),
+            Box::new(ExampleMapDynamic::new()),
+            Box::new(ExampleMapMarkers::new()),
+        ];
+
+        for m in maps.iter_mut() {
+            if let Some(t) = m.get_mut::<Time>() {
+                t.0 = 10;
+            }
+        }
+        assert_eq!(maps[0].get::<Time>(), Some(&Time(10)));
+        assert_eq!(maps[1].get::<Time>(), Some(&Time(10)));
+        assert!(!maps[2].contains::<Time>());
+
+        // Marker keys look up the value.
+        let players = maps[2]
+            .get_dyn(std::any::TypeId::of::<MaxPlayers>())
+            .unwrap();
+        assert_eq!(players.downcast_ref::<u32>(), Some(&16));
+
+        assert_eq!(maps[0].insert(Time(5)), Ok(Some(Time(10))));
+        assert_eq!(maps[0].insert(5u64), Err(5));
+        assert_eq!(maps[1].insert(5u64), Ok(None));
+        assert_eq!(maps[1].insert(6u64), Ok(Some(5)));
+        assert_eq!(maps[1].get::<u64>(), Some(&6));
+
+        let ids = maps[1].type_ids();
+        assert_eq!(ids.len(), 5);
+        assert!(ids.contains(&std::any::TypeId::of::<u64>()));
+        assert!(ids.contains(&std::any::TypeId::of::<Metrics>()));
+
+        // Maps which iterate by traits can't hold new boxed values.
+        let mut iterable: Box<dyn DynTypemap> = Box::new(IterationExampleMap::new());
+        assert!(iterable.insert(IdContainer3(3)).is_err());
+        assert!(iterable.insert(IdContainer1(1)).is_ok_and(|x| x.is_some()));
+
+        // The typed helpers also work when the maps have to be Send.
+        let sendable: Box<dyn DynTypemap + Send> = Box::new(ExampleMapFixed::new());
+        assert!(sendable.contains::<Filesystem>());
+    }
+
+    #[test]
+    fn test_boxed_values() {
+        use std::any::{Any, TypeId};
+
+        let mut map = ExampleMapDynamic::new();
+        let old = map.insert_boxed(Box::new(Time(5))).unwrap().unwrap();
+        assert_eq!(old.downcast_ref::<Time>(), Some(&Time(0)));
+        assert!(map.insert_boxed(Box::new(7u8)).unwrap().is_none());
+
+        let time = map.get_by_type_id(TypeId::of::<Time>()).unwrap();
+        assert_eq!(time.downcast_ref::<Time>(), Some(&Time(5)));
+        *map.get_by_type_id_mut(TypeId::of::<u8>())
+            .unwrap()
+            .downcast_mut::<u8>()
+            .unwrap() += 1;
+        assert_eq!(map.get::<u8>(), Some(&8));
+        assert!(map.get_by_type_id(TypeId::of::<u16>()).is_none());
+
+        // Fixed maps hand back what they can't hold.
+        let mut fixed = ExampleMapFixed::new();
+        let rejected = fixed.insert_boxed(Box::new(7u8)).unwrap_err();
+        assert_eq!(rejected.downcast_ref::<u8>(), Some(&7));
+
+        let values: Vec<Box<dyn Any>> = vec![Box::new(Time(2)), Box::new(3u32), Box::new("x")];
+        let collected = values.into_iter().collect::<ExampleMapDynamic>();
+        assert_eq!(collected.get::<Time>(), Some(&Time(2)));
+        assert_eq!(collected.get::<u32>(), Some(&3));
+        assert_eq!(collected.get::<&'static str>(), Some(&"x"));
+
+        fixed.extend([Box::new(Time(9)) as Box<dyn Any>, Box::new(1u8)]);
+        assert_eq!(fixed.get_infallible::<Time>(), &Time(9));
+    }
+
+    #[test]
+    fn test_derive() {
+        let mut map = ExampleMapDerived::new();
+        assert_eq!(map.get_infallible::<Scaled>(), &Scaled(3));
+        assert_eq!(*map.get_infallible::<MaxPlayers>(), 16);
+        map.time = Time(5);
+        assert_eq!(map.get::<Time>(), Some(&Time(5)));
+        assert_eq!(map.insert(7u8), Ok(None));
+        assert_eq!(map.get::<u8>(), Some(&7));
+        assert_eq!(map.len(), 4);
+
+        #[derive(FixedTypemap)]
+        #[fixed_typemap(iterable_traits(IntegralId = "iter_integral_id"))]
+        struct Derived {
+            first: IdContainer1,
+            #[fixed_typemap(default = "IdContainer2(first.0 + 1)")]
+            second: IdContainer2,
+        }
+
+        let mut map = Derived::new();
+        map.first.0 = 10;
+        for i in map.iter_integral_id_mut() {
+            i.set_id(i.get_id() * 2);
+        }
+        let ids = <dyn IntegralId>::iter_as(&map)
+            .map(|x| x.get_id())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![20, 2]);
+        assert!(map.insert(IdContainer3(3)).is_err());
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(dynamic, iterable_traits(IntegralId = "iter_integral_id"))]
+        struct ConfiguredMap {
+            // Other attributes inside `cfg_attr` stay on the field.
+            #[cfg_attr(test, doc = "Always present in tests.")]
+            first: IdContainer1 = IdContainer1(1),
+            #[cfg(not(test))]
+            _: IdContainer2,
+            #[cfg_attr(test, cfg(any()))]
+            _: IdContainer3,
+            #[cfg(test)]
+            #[fixed_typemap(lazy)]
+            _: IdContainer4 = IdContainer4(4),
+        }
+    }
+
+    #[test]
+    fn test_cfg() {
+        let mut map = ConfiguredMap::new();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get_infallible::<IdContainer4>().0, 4);
+        assert!(map.get::<IdContainer2>().is_none());
+        assert!(map.get::<IdContainer3>().is_none());
+
+        let mut ids = map
+            .iter_integral_id()
+            .map(|x| x.get_id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 4]);
+
+        // Configured out entries go to the dynamic section like any other type.
+        map.insert(IdContainer2(2)).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.iter_integral_id_mut().count(), 3);
+    }
+
+    #[test]
+    fn test_no_prelude() {
+        use super::no_prelude::{NoPreludeDerived, NoPreludeMap, NoPreludeReplicated};
+
+        let mut map = NoPreludeMap::new();
+        map.insert(IdContainer4(4)).unwrap();
+        let mut ids = map
+            .iter_integral_id()
+            .map(|x| x.get_id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 2, 3, 4]);
+
+        let mut map = NoPreludeDerived::new();
+        map.insert(IdContainer2(2)).unwrap();
+        assert_eq!(map.iter_integral_id().count(), 2);
+
+        let mut map = NoPreludeReplicated::new();
+        map.apply_config([("volume", "0.25"), ("motd", "Hi")])
+            .unwrap();
+        map.insert(5u8).unwrap();
+        let mut saved = vec![];
+        map.save(&mut saved).unwrap();
+        let mut loaded = NoPreludeReplicated::new();
+        loaded.load(&mut saved.as_slice()).unwrap();
+        assert_eq!(loaded.volume, 0.25);
+
+        let diff = loaded.diff(&map);
+        assert_eq!(diff.get::<u8>(), Some(&5));
+        loaded.apply(diff);
+        assert!(loaded.diff(&map).is_empty());
+    }
+
+    /// Already taken, so the map below names its cell type something else.
+    #[allow(dead_code)]
+    struct RenamedCellMapCell;
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(dynamic, cell_name = "RenamedCellMapEntry", iterable_traits(IntegralId = "iter_integral_id"))]
+        pub(crate) struct RenamedCellMap {
+            _: IdContainer1,
+        }
+    }
+
+    #[test]
+    fn test_cell_name() {
+        let mut map = RenamedCellMap::new();
+        map.insert(IdContainer2(2)).unwrap();
+
+        assert_eq!(map.iter_integral_id().count(), 2);
+
+        // The cell can be named, which is what it's there for.
+        assert!(std::mem::size_of::<RenamedCellMapEntry>() > 0);
+    }
+
+    fn settings() -> Settings {
+        Settings { scale: 2 }
+    }
+
+    decl_fixed_typemap! {
+        struct AccessorMap {
+            _: Settings = settings(),
+            // This is still the function, not the entry above.
+            _: Scaled = Scaled(settings().scale * 3),
+            _: (u8, u8),
+            #[fixed_typemap(accessor = "max")]
+            _: MaxPlayers => u32 = 16,
+            #[fixed_typemap(accessor = "port")]
+            listen_port: Port = Port(80),
+            #[fixed_typemap(lazy, boxed)]
+            _: BigTable,
+        }
+    }
+
+    #[test]
+    fn test_accessors() {
+        let mut map = AccessorMap::new();
+        assert_eq!(map.settings().scale, 2);
+        assert_eq!(map.scaled(), &Scaled(6));
+        assert_eq!(map.typemap_0(), &(0, 0));
+        assert_eq!(*map.max(), 16);
+        *map.max_mut() = 3;
+        assert_eq!(*map.get_infallible::<MaxPlayers>(), 3);
+        map.port_mut().0 = 8080;
+        assert_eq!(map.listen_port, Port(8080));
+        map.big_table_mut().0[1] = 5;
+        assert_eq!(map.big_table().0[..2], [0, 5]);
+
+        // The fields are named after the types too.
+        map.scaled = Scaled(1);
+        assert_eq!(map.get::<Scaled>(), Some(&Scaled(1)));
+    }
+
+    /// Its accessors are `insert_fixed` and `insert_fixed_mut`, which mustn't collide with the map's internal helpers.
+    #[derive(Default)]
+    struct InsertFixed(u8);
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(dynamic)]
+        struct HelperNamedMap {
+            _: InsertFixed,
+        }
+    }
+
+    #[test]
+    fn test_accessor_named_like_helper() {
+        let mut map = HelperNamedMap::new();
+        map.insert_fixed_mut().0 = 1;
+        assert_eq!(map.insert(InsertFixed(2)).unwrap().unwrap().0, 1);
+        assert_eq!(map.insert_fixed().0, 2);
+    }
+
+    #[derive(Default)]
+    struct HTTPServer;
+
+    #[derive(Default)]
+    struct XMLHttpRequest;
+
+    decl_fixed_typemap! {
+        struct AcronymMap {
+            _: HTTPServer,
+            _: XMLHttpRequest,
+        }
+    }
+
+    #[test]
+    fn test_acronym_names() {
+        let mut map = AcronymMap::new();
+        let _: &HTTPServer = map.http_server();
+        let _: &mut XMLHttpRequest = map.xml_http_request_mut();
+        assert_eq!(
+            AcronymMapKey::ALL,
+            [AcronymMapKey::HttpServer, AcronymMapKey::XmlHttpRequest]
+        );
+    }
+
+    #[test]
+    fn test_key_enum() {
+        use std::any::TypeId;
+        use std::convert::TryFrom;
+
+        assert_eq!(AccessorMapKey::ALL.len(), 6);
+        assert_eq!(AccessorMapKey::ALL[1], AccessorMapKey::Scaled);
+        assert_eq!(AccessorMapKey::Typemap0.index(), 2);
+        assert_eq!(
+            AccessorMapKey::ListenPort.type_name(),
+            std::any::type_name::<Port>()
+        );
+        assert_eq!(
+            TypeId::from(AccessorMapKey::MaxPlayers),
+            TypeId::of::<MaxPlayers>()
+        );
+        assert_eq!(
+            AccessorMapKey::try_from(TypeId::of::<BigTable>()),
+            Ok(AccessorMapKey::BigTable)
+        );
+        assert_eq!(
+            AccessorMapKey::try_from(TypeId::of::<u32>()),
+            Err(TypeId::of::<u32>())
+        );
+
+        let mut map = AccessorMap::new();
+        assert_eq!(AccessorMap::index_of::<Port>(), Some(4));
+        assert_eq!(AccessorMap::index_of::<u32>(), None);
+        *map.get_by_index_mut(3).downcast_mut::<u32>().unwrap() = 3;
+        assert_eq!(*map.max(), 3);
+        for key in AccessorMapKey::ALL {
+            assert_eq!(
+                (*map.get_by_index(key.index())).type_id(),
+                match key {
+                    AccessorMapKey::MaxPlayers => TypeId::of::<u32>(),
+                    _ => key.type_id(),
+                }
+            );
+        }
+
+        // Configured out entries have no variant, and the rest are numbered without them.
+        assert_eq!(
+            ConfiguredMapKey::ALL,
+            [ConfiguredMapKey::First, ConfiguredMapKey::IdContainer4]
+        );
+        assert_eq!(ConfiguredMap::index_of::<IdContainer4>(), Some(1));
+        assert!(ConfiguredMap::new()
+            .get_by_index(1)
+            .downcast_ref::<IdContainer4>()
+            .is_some());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_by_index_out_of_range() {
+        AccessorMap::new().get_by_index(AccessorMapKey::ALL.len());
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(config)]
+        struct RawNamesMap {
+            r#type: u32 = 1,
+            pub r#match: u16 = 80,
+        }
+    }
+
+    #[derive(FixedTypemap)]
+    struct RawNamesDerived {
+        r#type: u32,
+    }
+
+    #[test]
+    fn test_raw_identifiers() {
+        let mut map = RawNamesMap::new();
+        assert_eq!(
+            RawNamesMapKey::ALL,
+            [RawNamesMapKey::Type, RawNamesMapKey::Match]
+        );
+
+        // Names as strings don't have the `r#`.
+        assert_eq!(RawNamesMapKey::Type.name(), "type");
+        assert_eq!(map.names().collect::<Vec<_>>(), vec!["type", "match"]);
+        map.apply_config([("type", "2")]).unwrap();
+        assert_eq!(map.r#type, 2);
+        assert!(map.get_by_name("match").unwrap().is::<u16>());
+
+        assert_eq!(RawNamesDerivedKey::Type.name(), "type");
+        assert_eq!(*RawNamesDerived::new().get_infallible::<u32>(), 0);
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let mut map = ExampleMapConfig::new();
+        map.apply_config([("volume", "0.25"), ("players.max", "32"), ("motd", "Hi")])
+            .unwrap();
+        assert_eq!(map.volume, 0.25);
+        assert_eq!(*map.get_infallible::<MaxPlayers>(), 32);
+        assert_eq!(map.get_infallible::<String>(), "Hi");
+
+        let errors = map
+            .apply_config(vec![
+                ("volume", "loud"),
+                ("metrics", "none"),
+                ("players.max", "8"),
+                ("time", "5"),
+            ])
+            .unwrap_err();
+        let keys = errors.errors().iter().map(|e| e.key()).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["volume", "metrics", "time"]);
+        assert!(matches!(&errors.errors()[0], ConfigError::Parse { value, .. } if value == "loud"));
+        assert_eq!(errors.errors()[1], ConfigError::unknown_key("metrics"));
+
+        // Everything that could be applied was.
+        assert_eq!(map.volume, 0.25);
+        assert_eq!(*map.get_infallible::<MaxPlayers>(), 8);
+    }
+
+    #[test]
+    fn test_type_registry() {
+        let mut registry = TypeRegistry::<IterationExampleMap, u64>::new();
+        registry
+            .register("id3", IdContainer3)
+            .register_default::<IdContainer4>("id4")
+            .register("id1", |id| IdContainer1(id * 10));
+        assert!(registry.contains("id4"));
+        assert_eq!(
+            registry.type_id("id3"),
+            Some(std::any::TypeId::of::<IdContainer3>())
+        );
+
+        let mut map = IterationExampleMap::new();
+        assert!(map.insert_by_name(&registry, "id3", 3).unwrap().is_none());
+        map.insert_by_name(&registry, "id4", 100).unwrap();
+        let old = map.insert_by_name(&registry, "id1", 1).unwrap().unwrap();
+        assert_eq!(old.downcast_ref::<IdContainer1>().unwrap().0, 0);
+        assert_eq!(
+            map.insert_by_name(&registry, "id5", 5).unwrap_err(),
+            RegistryError::UnknownName("id5".to_string())
+        );
+
+        // Registered types can be iterated by the map's traits.
+        let mut ids = map
+            .iter_integral_id()
+            .map(|x| x.get_id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 0, 3, 10]);
+
+        // Maps without a dynamic section can only take their own types.
+        let mut registry = TypeRegistry::<ExampleMapFixed>::new();
+        registry.register_default::<Time>("time");
+        registry.register("port", |_| Port(80));
+        let mut map = ExampleMapFixed::new();
+        map.insert_by_name(&registry, "time", ()).unwrap();
+        assert_eq!(
+            map.insert_by_name(&registry, "port", ()).unwrap_err(),
+            RegistryError::Rejected("port".to_string())
+        );
+    }
+
+    #[derive(StableKey, Default, Debug, PartialEq)]
+    struct Score(u64);
+
+    impl Codec for Score {
+        fn encode(&self, out: &mut Vec<u8>) {
+            self.0.encode(out);
+        }
+
+        fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+            u64::decode(input).map(Score)
+        }
+    }
+
+    /// Version 1 saved the maximum number of players as a `u8`.
+    fn migrate_max_players(version: u32, bytes: &[u8]) -> Result<u32, CodecError> {
+        assert_eq!(version, 1);
+        u8::decode_all(bytes).map(u32::from)
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(persist(version = 2))]
+        struct PersistedMap {
+            #[fixed_typemap(key = "volume")]
+            volume: f32 = 0.5,
+            #[fixed_typemap(key = "players.max", migrate = "migrate_max_players")]
+            _: MaxPlayers => u32 = 16,
+            _: Score,
+            #[fixed_typemap(lazy, key = "motd")]
+            _: String = String::from("Welcome!"),
+            #[fixed_typemap(persist = false)]
+            _: Metrics = build_initial_metrics(),
+        }
+    }
+
+    #[test]
+    fn test_persistence() {
+        assert_eq!(
+            <Score as StableKey>::KEY,
+            "fixed_typemap::example::tests::Score"
+        );
+
+        let mut map = PersistedMap::new();
+        map.volume = 0.25;
+        *map.get_infallible_mut::<MaxPlayers>() = 32;
+        map.get_infallible_mut::<Score>().0 = 100;
+        *map.get_infallible_mut::<String>() = "Hi".to_string();
+        let mut saved = vec![];
+        map.save(&mut saved).unwrap();
+
+        let mut loaded = PersistedMap::new();
+        loaded.load(&mut saved.as_slice()).unwrap();
+        assert_eq!(loaded.volume, 0.25);
+        assert_eq!(*loaded.get_infallible::<MaxPlayers>(), 32);
+        assert_eq!(loaded.get_infallible::<Score>(), &Score(100));
+        assert_eq!(loaded.get_infallible::<String>(), "Hi");
+
+        // Older data is migrated, and entries it doesn't have or that no longer exist are left alone.
+        let mut old = vec![];
+        write_saved(
+            &mut old,
+            1,
+            &[("players.max", vec![8]), ("removed", vec![])],
+        )
+        .unwrap();
+        let mut loaded = PersistedMap::new();
+        loaded.load(&mut old.as_slice()).unwrap();
+        assert_eq!(*loaded.get_infallible::<MaxPlayers>(), 8);
+        assert_eq!(loaded.volume, 0.5);
+
+        let mut newer = vec![];
+        write_saved(&mut newer, 3, &[]).unwrap();
+        assert!(matches!(
+            loaded.load(&mut newer.as_slice()),
+            Err(PersistError::NewerVersion {
+                saved: 3,
+                current: 2
+            })
+        ));
+        assert!(matches!(
+            loaded.load(&mut &b"not a map"[..]),
+            Err(PersistError::BadFormat(_))
+        ));
+
+        // A bad entry means nothing is loaded, even entries before it.
+        let mut bad = vec![];
+        write_saved(
+            &mut bad,
+            2,
+            &[
+                ("volume", 1.0f32.to_le_bytes().to_vec()),
+                (Score::KEY, vec![1]),
+            ],
+        )
+        .unwrap();
+        match loaded.load(&mut bad.as_slice()) {
+            Err(PersistError::Decode { key, .. }) => assert_eq!(key, Score::KEY),
+            _ => panic!("expected a decoding error"),
+        }
+        assert_eq!(loaded.volume, 0.5);
+    }
+
+    /// Types with the same name in different modules, which mustn't be saved under the same key.
+    mod audio {
+        use crate::{Codec, CodecError, StableKey};
+
+        #[derive(StableKey, Default, Debug, PartialEq)]
+        pub struct Settings(pub u32);
+
+        impl Codec for Settings {
+            fn encode(&self, out: &mut Vec<u8>) {
+                self.0.encode(out);
+            }
+
+            fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+                u32::decode(input).map(Settings)
+            }
+        }
+    }
+
+    mod video {
+        use crate::{Codec, CodecError, StableKey};
+
+        #[derive(StableKey, Default, Debug, PartialEq)]
+        pub struct Settings(pub u32);
+
+        impl Codec for Settings {
+            fn encode(&self, out: &mut Vec<u8>) {
+                self.0.encode(out);
+            }
+
+            fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+                u32::decode(input).map(Settings)
+            }
+        }
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(persist(version = 1))]
+        struct SettingsMap {
+            _: audio::Settings,
+            _: video::Settings,
+        }
+    }
+
+    #[test]
+    fn test_persistence_same_type_names() {
+        let mut map = SettingsMap::new();
+        map.get_infallible_mut::<audio::Settings>().0 = 1;
+        map.get_infallible_mut::<video::Settings>().0 = 2;
+        let mut saved = vec![];
+        map.save(&mut saved).unwrap();
+
+        let mut loaded = SettingsMap::new();
+        loaded.load(&mut saved.as_slice()).unwrap();
+        assert_eq!(
+            loaded.get_infallible::<audio::Settings>(),
+            &audio::Settings(1)
+        );
+        assert_eq!(
+            loaded.get_infallible::<video::Settings>(),
+            &video::Settings(2)
+        );
+    }
+
+    /// Collects the names of the keys it visits.
+    #[derive(Default)]
+    struct KeyNames(Vec<&'static str>);
+
+    impl<T: ?Sized> TypemapVisitor<T> for KeyNames {
+        fn visit<K: std::any::Any>(&mut self, _value: &T) {
+            self.0.push(std::any::type_name::<K>());
+        }
+    }
+
+    /// Resets every entry, which needs `Default` and so couldn't be done through trait objects.
+    struct Reset;
+
+    impl<T: Default> TypemapVisitorMut<T> for Reset {
+        fn visit_mut<K: std::any::Any>(&mut self, value: &mut T) {
+            *value = T::default();
+        }
+    }
+
+    #[test]
+    fn test_visit() {
+        let map = AccessorMap::new();
+        let mut names = KeyNames::default();
+        map.visit(&mut names);
+        let expected = [
+            std::any::type_name::<Settings>(),
+            std::any::type_name::<Scaled>(),
+            std::any::type_name::<(u8, u8)>(),
+            std::any::type_name::<MaxPlayers>(),
+            std::any::type_name::<Port>(),
+            std::any::type_name::<BigTable>(),
+        ];
+        assert_eq!(names.0, expected);
+
+        let mut map = ConfiguredMap::new();
+        map.first.0 = 10;
+        map.get_infallible_mut::<IdContainer4>().0 = 40;
+        map.visit_mut(&mut Reset);
+        assert_eq!(map.first.0, 0);
+        assert_eq!(map.get_infallible::<IdContainer4>().0, 0);
+    }
+
+    /// Moves every value of the left map halfway towards the right one.
+    #[derive(Default)]
+    struct Interpolate {
+        dynamic: Vec<&'static str>,
+    }
+
+    impl<T: IntegralId + ?Sized> TypemapZipVisitorMut<T> for Interpolate {
+        fn visit_mut<K: std::any::Any>(&mut self, left: &mut T, right: &mut T) {
+            left.set_id((left.get_id() + right.get_id()) / 2);
+        }
+    }
+
+    impl DynamicZipVisitorMut for Interpolate {
+        fn visit_dynamic_mut(
+            &mut self,
+            _key: std::any::TypeId,
+            entry: Zipped<&mut dyn std::any::Any>,
+        ) {
+            self.dynamic.push(match entry {
+                Zipped::Both(..) => "both",
+                Zipped::LeftOnly(..) => "left",
+                Zipped::RightOnly(..) => "right",
+            });
+        }
+    }
+
+    /// Counts the entries which differ between two maps.
+    struct CountDifferences(usize);
+
+    impl<T: IntegralId + ?Sized> TypemapZipVisitor<T> for CountDifferences {
+        fn visit<K: std::any::Any>(&mut self, left: &T, right: &T) {
+            self.0 += (left.get_id() != right.get_id()) as usize;
+        }
+    }
+
+    impl DynamicZipVisitor for CountDifferences {
+        fn visit_dynamic(&mut self, _key: std::any::TypeId, entry: Zipped<&dyn std::any::Any>) {
+            self.0 += !matches!(entry, Zipped::Both(..)) as usize;
+        }
+    }
+
+    #[test]
+    fn test_zip_visit() {
+        let mut left = IterationExampleMap::new();
+        let mut right = IterationExampleMap::new();
+        right.get_infallible_mut::<IdContainer1>().0 = 10;
+        left.insert(IdContainer3(0)).unwrap();
+        left.insert(IdContainer4(0)).unwrap();
+        right.insert(IdContainer4(0)).unwrap();
+
+        let mut differences = CountDifferences(0);
+        left.zip_visit(&right, &mut differences);
+        assert_eq!(differences.0, 2);
+
+        let mut interpolate = Interpolate::default();
+        left.zip_visit_mut(&mut right, &mut interpolate);
+        assert_eq!(left.get_infallible::<IdContainer1>().0, 5);
+        interpolate.dynamic.sort();
+        assert_eq!(interpolate.dynamic, vec!["both", "left"]);
+
+        // Types only in the map passed in are on the right.
+        let mut interpolate = Interpolate::default();
+        IterationExampleMap::zip_visit_mut(&mut right, &mut left, &mut interpolate);
+        interpolate.dynamic.sort();
+        assert_eq!(interpolate.dynamic, vec!["both", "right"]);
+    }
+
+    #[test]
+    fn test_get_by_name() {
+        let mut map = ExampleMapDynamic::new();
+        map.insert(Port(80)).unwrap();
+        map.insert_boxed(Box::new(5u8)).unwrap();
+
+        assert_eq!(
+            map.get_by_name("name").unwrap().downcast_ref::<String>(),
+            Some(&String::new())
+        );
+        map.get_by_name_mut("time")
+            .unwrap()
+            .downcast_mut::<Time>()
+            .unwrap()
+            .0 = 5;
+        assert_eq!(map.get_infallible::<Time>(), &Time(5));
+
+        // Type names work for both sections, except for values which were inserted boxed.
+        let filesystem = std::any::type_name::<Filesystem>();
+        assert!(map.get_by_name(filesystem).unwrap().is::<Filesystem>());
+        let port = std::any::type_name::<Port>();
+        map.get_by_name_mut(port)
+            .unwrap()
+            .downcast_mut::<Port>()
+            .unwrap()
+            .0 = 8080;
+        assert_eq!(map.get::<Port>(), Some(&Port(8080)));
+        assert!(map.get_by_name("u8").is_none());
+        assert!(map.get_by_name("missing").is_none());
+
+        assert_eq!(
+            map.names().collect::<Vec<_>>(),
+            vec!["name", "time", "filesystem", "metrics", port]
+        );
+        assert_eq!(
+            ExampleMapDynamicKey::from_name("metrics"),
+            Some(ExampleMapDynamicKey::Metrics)
+        );
+        assert_eq!(ExampleMapDynamicKey::Filesystem.name(), "filesystem");
+    }
+
     #[test]
     fn test_fallible_getters() {
         let mut map = ExampleMapDynamic::new();
@@ -263,6 +1476,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_boxed_insert_with_traits() {
+        let mut map = TestFixedIteration::new();
+        map.insert(IdContainer3(3)).unwrap();
+
+        // Boxed values can replace a dynamic value of the same type, but new types can't be cast to the traits.
+        let old = map
+            .insert_boxed(Box::new(IdContainer3(5)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(old.downcast_ref::<IdContainer3>().unwrap().0, 3);
+        assert!(map.insert_boxed(Box::new(IdContainer4(4))).is_err());
+
+        let mut ids = map
+            .iter_integral_id()
+            .map(|x| x.get_id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 0, 5]);
+    }
+
     #[test]
     fn test_iterating_fixed() {
         let mut map = TestFixedIteration::new();
@@ -309,6 +1543,43 @@ mod tests {
         assert_eq!(ids2, vec![2, 3, 4, 5]);
     }
 
+    #[test]
+    fn test_get_as() {
+        use std::any::TypeId;
+
+        let mut map = IterationExampleMap::new();
+        map.insert(IdContainer3(3)).unwrap();
+
+        assert_eq!(
+            map.get_as_integral_id(TypeId::of::<IdContainer1>())
+                .unwrap()
+                .get_id(),
+            0
+        );
+        map.get_as_integral_id_mut(TypeId::of::<IdContainer3>())
+            .unwrap()
+            .set_id(30);
+        assert_eq!(
+            map.get_as_display(TypeId::of::<IdContainer3>())
+                .unwrap()
+                .to_string(),
+            "id3=30"
+        );
+        assert!(map
+            .get_as_integral_id(TypeId::of::<IdContainer4>())
+            .is_none());
+
+        let ids = [TypeId::of::<IdContainer2>(), TypeId::of::<IdContainer3>()]
+            .into_iter()
+            .map(|id| <dyn IntegralId>::get_as(&map, id).unwrap().get_id())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 30]);
+        <dyn IntegralId>::get_as_mut(&mut map, TypeId::of::<IdContainer2>())
+            .unwrap()
+            .set_id(2);
+        assert_eq!(map.get::<IdContainer2>().unwrap().0, 2);
+    }
+
     #[test]
     fn test_iterable_as_dynamic() {
         let mut map = IterationExampleMap::new();
@@ -394,4 +1665,170 @@ mod tests {
         ids2.sort();
         assert_eq!(ids2, vec![2, 3, 4, 5]);
     }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(dynamic, diff)]
+        struct ReplicatedMap {
+            pub volume: f32 = 0.5,
+            _: MaxPlayers => u32 = 16,
+            #[fixed_typemap(lazy)]
+            _: String = String::from("Welcome!"),
+            _: Time,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        use crate::DynamicChange;
+        use std::any::TypeId;
+
+        #[derive(Clone, Debug, PartialEq)]
+        struct Weather(&'static str);
+
+        let server_start = ReplicatedMap::new();
+        let mut server = ReplicatedMap::new();
+        assert!(server_start.diff(&server).is_empty());
+
+        server.volume = 0.75;
+        *server.get_infallible_mut::<MaxPlayers>() = 32;
+        server.insert(Weather("rain")).unwrap();
+        server.insert(5u8).unwrap();
+
+        let mut client = ReplicatedMap::new();
+        client.insert(5u8).unwrap();
+        client.insert(7u16).unwrap();
+
+        let diff = client.diff(&server);
+        assert_eq!(
+            diff.changed().collect::<Vec<_>>(),
+            vec![ReplicatedMapKey::Volume, ReplicatedMapKey::MaxPlayers]
+        );
+        let mut dynamic = diff
+            .dynamic_changes()
+            .map(|(id, change)| (id, change.map(|_| ())))
+            .collect::<Vec<_>>();
+        dynamic.sort_by_key(|(id, _)| *id != TypeId::of::<Weather>());
+        assert_eq!(
+            dynamic,
+            vec![
+                (TypeId::of::<Weather>(), DynamicChange::Inserted(())),
+                (TypeId::of::<u16>(), DynamicChange::Removed),
+            ]
+        );
+
+        // The new values can be read back out.
+        assert_eq!(diff.get::<f32>(), Some(&0.75));
+        assert_eq!(diff.get_keyed::<MaxPlayers>(), Some(&32));
+        assert_eq!(diff.get::<Weather>(), Some(&Weather("rain")));
+        assert_eq!(diff.get::<u8>(), None);
+        let mut names = KeyNames::default();
+        diff.visit(&mut names);
+        assert_eq!(names.0, vec!["f32", std::any::type_name::<MaxPlayers>()]);
+
+        client.apply(diff);
+        assert_eq!(client.volume, 0.75);
+        assert_eq!(*client.get_infallible::<MaxPlayers>(), 32);
+        assert_eq!(client.get::<Weather>(), Some(&Weather("rain")));
+        assert_eq!(client.get::<u16>(), None);
+        assert!(client.diff(&server).is_empty());
+
+        server.insert(Weather("snow")).unwrap();
+        *server.get_infallible_mut::<String>() = "Hi".to_string();
+        let diff = client.diff(&server);
+        assert_eq!(
+            diff.changed().collect::<Vec<_>>(),
+            vec![ReplicatedMapKey::String]
+        );
+        let changes = diff.dynamic_changes().collect::<Vec<_>>();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0, TypeId::of::<Weather>());
+        match changes[0].1 {
+            DynamicChange::Changed(value) => {
+                assert_eq!(value.downcast_ref::<Weather>(), Some(&Weather("snow")))
+            }
+            _ => panic!("expected a change"),
+        }
+        client.apply(diff);
+        assert_eq!(client.get_infallible::<String>(), "Hi");
+        assert_eq!(client.get::<Weather>(), Some(&Weather("snow")));
+
+        // Values of types already in the dynamic section can be replaced through boxes, even though new ones can't
+        // be added that way.
+        assert_eq!(
+            client
+                .insert_boxed(Box::new(6u8))
+                .unwrap()
+                .unwrap()
+                .downcast_ref::<u8>(),
+            Some(&5)
+        );
+        client.extend([Box::new(7u8) as Box<dyn std::any::Any>]);
+        assert_eq!(client.get::<u8>(), Some(&7));
+        assert!(client.insert_boxed(Box::new(8u64)).is_err());
+    }
+
+    /// Already taken, so the map below names its diff something else.
+    #[allow(dead_code)]
+    struct RenamedDiffMapDiff;
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(diff, diff_name = "RenamedDiffMapChanges")]
+        struct RenamedDiffMap {
+            _: u32,
+        }
+    }
+
+    #[test]
+    fn test_diff_name() {
+        let mut map = RenamedDiffMap::new();
+        let mut other = RenamedDiffMap::new();
+        *other.get_infallible_mut::<u32>() = 3;
+
+        let diff: RenamedDiffMapChanges = map.diff(&other);
+        assert_eq!(diff.get::<u32>(), Some(&3));
+        map.apply(diff);
+        assert_eq!(*map.get_infallible::<u32>(), 3);
+    }
+
+    decl_fixed_typemap! {
+        #[fixed_typemap(diff, persist(version = 1))]
+        struct ReplicatedSettings {
+            #[fixed_typemap(key = "volume")]
+            volume: f32 = 0.5,
+            #[fixed_typemap(key = "players.max")]
+            _: MaxPlayers => u32 = 16,
+            #[fixed_typemap(key = "motd")]
+            _: String,
+            #[fixed_typemap(persist = false)]
+            _: u8,
+        }
+    }
+
+    #[test]
+    fn test_diff_codec() {
+        let client_start = ReplicatedSettings::new();
+        let mut server = ReplicatedSettings::new();
+        *server.get_infallible_mut::<MaxPlayers>() = 32;
+        *server.get_infallible_mut::<String>() = "Hi".to_string();
+        *server.get_infallible_mut::<u8>() = 5;
+
+        // Only the changed entries are sent, and those which aren't persisted are left out.
+        let mut bytes = vec![];
+        client_start.diff(&server).encode(&mut bytes);
+        let received = ReplicatedSettingsDiff::decode_all(&bytes).unwrap();
+        assert_eq!(
+            received.changed().collect::<Vec<_>>(),
+            vec![
+                ReplicatedSettingsKey::MaxPlayers,
+                ReplicatedSettingsKey::String
+            ]
+        );
+
+        let mut client = ReplicatedSettings::new();
+        client.apply(received);
+        assert_eq!(*client.get_infallible::<MaxPlayers>(), 32);
+        assert_eq!(client.get_infallible::<String>(), "Hi");
+        assert_eq!(*client.get_infallible::<u8>(), 0);
+        assert!(ReplicatedSettingsDiff::decode_all(&bytes[..bytes.len() - 1]).is_err());
+    }
 }
@@ -11,10 +11,32 @@
 //!   - They can also be accessed by `get`, which works additionally in dynamic contexts.
 //!   - Or via the [InfallibleKey] trait, for dynamic code which wishes to accept any map that is known to contain some
 //!     type.
+//! - Every map implements [Typemap], so code can be generic over any map and look types up fallibly.
+//! - Every map also implements the object-safe [DynTypemap], so maps declared by different crates can be stored behind
+//!   one pointer such as `Box<dyn DynTypemap>`.
+//! - Runtime access through `TypeId` and `Box<dyn Any>` with `get_by_type_id`, `get_by_type_id_mut` and `insert_boxed`,
+//!   plus `Extend` and `FromIterator` over boxed values, for scripting and deserialization code which doesn't know
+//!   types statically.  `Extend` and `FromIterator` drop values which `insert_boxed` would refuse.
+//! - A generated `{Name}Key` enum with a variant per fixed entry, and `index_of` and `get_by_index`, for code which
+//!   needs a small, matchable handle on an entry, such as editors, network protocols, and bitsets of changed entries.
+//! - Lookup by name with `get_by_name` and `get_by_name_mut`, which find fixed entries by field name and any entry by
+//!   the `core::any::type_name` of its key, and `names` to list them, for consoles and config files.
+//! - A [TypeRegistry] of constructors for types known by name, so that scripts and save files can fill the dynamic
+//!   section with `insert_by_name`.
+//! - Saving and loading fixed entries without serde, through [StableKey] names and a [Codec], with versioned data and
+//!   per-entry migrations.
+//! - Static visiting of every fixed entry with `visit` and `visit_mut`, where the [TypemapVisitor] is called once per
+//!   entry with its concrete type.  Unlike iterating by traits, this works with traits which aren't object safe, such
+//!   as `Hash` or `Default`, and has no dynamic dispatch.
+//!   - `zip_visit` and `zip_visit_mut` do the same for two maps of the same type side by side, for interpolation and
+//!     replication.  Dynamic sections are matched up by type, with each type reported as [Zipped]`::Both`,
+//!     `LeftOnly` or `RightOnly`.
+//! - Diffing two maps of the same type with `diff`, which lists what changed by `PartialEq`, and replaying the result
+//!   with `apply`, so replicated maps only need to send changes.
 //! - Ability to name fields of the generated struct, and to forward attributes (e.g. you can tag things with serde).
 //! - If not using support for dynamic typemaps, no allocation.
 //!   - In theory also `no_std` but I don't know enough about that to be sure I'm testing it right; if you want to help,
-//!       finishing it will take about an hour.
+//!     finishing it will take about an hour.
 //! - Ability to declare a list of traits you want to iterate by.  Mutable iteration is supported, and the returned
 //!   iterators don't require boxing.
 //! - As a consequence of no allocation, fixed maps don't pointer chase and are as big as the combined types.
@@ -173,19 +195,278 @@
 //! explicitly for example, then chances are `Serde` won't do what you want.  The syntax of a field is:
 //!
 //! ```ignore
-//! (_ | ident): type [= expr],
+//! (_ | ident): type [=> value_type] [= expr],
 //! ```
 //!
 //! The extensions here being `_` as a field name when you don't care about the name, and `= expression` to specify a
 //! default value.  The macro requires that all fields either impl `Default` or have a provided expression.
 //!
+//! Fields declared as `_` are named after the last segment of their key's path in snake_case, so `_: SoundPlugin` is
+//! the field `sound_plugin`, and get accessor methods `sound_plugin()` and `sound_plugin_mut()`.  Acronyms are one word,
+//! so `_: HTTPServer` is `http_server`.  Types which can't be named that way, such as tuples, or whose name is taken, are named `typemap_0`, `typemap_1` and so on instead.  These
+//! names don't shadow anything used by the initializers of later entries.
+//!
+//! Normally the type of a field is both the key and the stored value.  `Key => Value` instead uses `Key`, usually a
+//! marker struct, as the key and stores a `Value`.  This is how a map can hold more than one value of the same type:
+//!
+//! ```ignore
+//! _: MaxPlayers => u32 = 16,
+//! _: MinPlayers => u32 = 2,
+//! ```
+//!
+//! Then `get_infallible::<MaxPlayers>()` returns a `&u32`.  Since the fallible `get::<K>()` returns a `K`, it doesn't
+//! find these entries; implement [TypemapKey] for the marker and use `get_keyed`, `get_keyed_mut` and `insert_keyed`
+//! instead, which also work with the dynamic section.
+//!
+//! Fields are initialized in declaration order, and an initializer may refer to any named field declared before it as
+//! if it were a local variable.  This is how one entry can be built from another:
+//!
+//! ```ignore
+//! config: Config = load_config(),
+//! _: Renderer = Renderer::new(&config),
+//! ```
+//!
+//! A field's own name isn't in scope in its initializer, so `config: Config = config()` calls the function.  Referring
+//! to a field declared later is an error, which points at the offending name:
+//!
+//! ```compile_fail
+//! # use fixed_typemap::decl_fixed_typemap;
+//! decl_fixed_typemap! {
+//!     struct Broken {
+//!         doubled: u64 = base * 2,
+//!         base: u64 = 5,
+//!     }
+//! }
+//! ```
+//!
+//! Fields may be behind `#[cfg(...)]`, or `#[cfg_attr(...)]` which expands to `cfg`, in which case everything generated
+//! for the entry is behind it too.  An entry which is configured out isn't part of the map at all, so its type can be
+//! used with the dynamic section like any other.
+//!
+//! Each type can only key one entry.  Using a type twice is an error pointing at both entries, and this is also caught
+//! when the two are spelled differently, e.g. through a type alias:
+//!
+//! ```compile_fail
+//! # use fixed_typemap::decl_fixed_typemap;
+//! type Count = u32;
+//!
+//! decl_fixed_typemap! {
+//!     struct Broken {
+//!         _: u32,
+//!         _: Count,
+//!     }
+//! }
+//! ```
+//!
 //! The `fixed_typemap` attribute can be used to control the generated struct:
 //!
 //! - `#[fixed_typemap(dynamic)]`: this typemap will have a dynamic section and can consequently hold any type. Requires
 //!   allocation.
 //! - `#[fixed_typemap(iterable_traits(path = "method_name", ... ))]`: generate a `method_name` and `method_name_mut`
 //!   trait pair which will iterate over the specified trait, as well as the appropriate [IterableAs] implementations.
+//!   Each trait also gets `get_as_trait_name` and `get_as_trait_name_mut`, named after the last segment of the trait's
+//!   path, which look up a single entry by `TypeId` as the trait object, and a matching [GettableAs] implementation.
+//!   Names which collide with each other or with the methods every map has are errors.
+//! - `#[fixed_typemap(fallible(error = "ErrorType"))]`: initializers may use `?`, and the map gets a `try_new() ->
+//!   Result<Self, ErrorType>` instead of `new` and `Default`.  If an initializer fails, the entries built before it are
+//!   dropped.  Lazy entries are built on first access rather than in `try_new`, so their initializers can't use `?`.
+//! - `#[fixed_typemap(lazy_iteration = "init" | "skip")]`: what trait iteration does with lazy entries that haven't been
+//!   built yet.  The default, `init`, builds them; `skip` leaves them alone and doesn't yield them.
+//! - `#[fixed_typemap(crate = "path")]`: where the generated code finds this crate, `::fixed_typemap` by default.  For
+//!   crates which re-export `fixed_typemap` and want their users to be able to declare maps without depending on it
+//!   directly.  Generated code otherwise only uses fully qualified paths, so it works in `#![no_implicit_prelude]`
+//!   modules and alongside other types named e.g. `Box` or `Default`.
+//! - `#[fixed_typemap(cell_name = "Name")]`: the name of the type which holds values in the dynamic section, which
+//!   is otherwise the name of the map followed by `Cell`.  This type is only exposed so that iterator types can be
+//!   named, and like the generated methods it has the same visibility as the map.
+//! - `#[fixed_typemap(key_enum = "Name")]`: the name of the enum with a variant per fixed entry, which is otherwise
+//!   the name of the map followed by `Key`.  Variants are named after the fields in camel case, so `_: SoundPlugin`
+//!   is `PluginMapKey::SoundPlugin`, and are in declaration order.  The enum has `ALL`, `index`, `type_name` and
+//!   `type_id`, plus `name` and `from_name` for the field, and converts to and from `TypeId`.  Entries declared as
+//!   `Key => Value` are identified by the key.  Every map has this enum, with the same visibility as the map, so
+//!   renaming it is also the way out if the default name is already taken.
+//! - `#[fixed_typemap(config)]`: generate `apply_config`, which takes `(key, value)` string pairs, e.g. from a settings
+//!   file, and parses each value into the entry named by the key with `FromStr`.  Pairs which can be applied are, and
+//!   the rest are reported together in a [ConfigErrors], as unknown keys or parse failures.  The key is the field name,
+//!   and entries declared as `_` aren't configurable unless they say so.  See `config` on fields, below.
+//! - `#[fixed_typemap(persist(version = N))]`: generate `save(&mut impl Write)` and `load(&mut impl Read)`.  Every
+//!   fixed entry is saved under the [StableKey] of its key type, encoded with the [Codec] of its value type, so both
+//!   must be implemented for it unless it opts out.  Two entries with the same key are a compile error.  The version is
+//!   saved with the data, and `load` refuses data from newer versions and passes older data through the migration
+//!   functions of entries.  Loading sets the entries in the data and leaves the rest alone, and changes nothing if any
+//!   entry fails.  The dynamic section isn't saved, since there would be no way to know what to decode its values as.
+//! - `#[fixed_typemap(diff)]`: generate `diff(&self, other: &Self)`, which returns a `{Name}Diff` holding what would
+//!   turn this map into `other`, and `apply(&mut self, diff)`, which makes those changes.  Fixed entries which differ
+//!   by `PartialEq` are cloned, as are values in the dynamic section which were inserted or changed, and values
+//!   missing from `other` are removed.  Every value has to implement `PartialEq` and `Clone`, including those inserted
+//!   into the dynamic section, so boxed values can only replace values of the same type.  The diff has `changed`,
+//!   which lists the changed fixed entries as variants of the key enum, `get` and `get_keyed` for the new values, and
+//!   `visit` for handing every changed fixed entry to a [TypemapVisitor].  `dynamic_changes` lists the dynamic
+//!   section's changes as [DynamicChange]s by `TypeId`.  If the map also has `persist`, the diff implements [Codec],
+//!   which writes the changed fixed entries under their stable keys so that the diff can be sent elsewhere; entries
+//!   which aren't persisted and the dynamic section are left out.
+//! - `#[fixed_typemap(diff_name = "Name")]`: the name of the type returned by `diff`, which is otherwise the name of the
+//!   map followed by `Diff`.  Like the key enum and the cell type, it has the same visibility as the map, and giving two
+//!   of these types the same name is an error.
+//! - `#[fixed_typemap(const_new)]`: make `new` a `const fn`, so that the map can be used to initialize statics and
+//!   constants.  Every entry which isn't lazy needs an initializer, and the initializers must be const.  This can't be
+//!   used with `dynamic` or `fallible`.  `get_infallible` goes through a trait and so can't be const, but named fields
+//!   can be read directly in const contexts.  Lazy entries of these maps are stored in a [std::sync::OnceLock] rather
+//!   than a `OnceCell`, so that the map can still be `Sync` and go in a `static`.
+//!
+//! Mistakes in these attributes, on the map or on its entries, are all reported at once, each pointing at itself:
+//!
+//! ```compile_fail
+//! # use fixed_typemap::decl_fixed_typemap;
+//! decl_fixed_typemap! {
+//!     #[fixed_typemap(dynamc)]
+//!     struct Broken {
+//!         #[fixed_typemap(lazzy)]
+//!         _: u32,
+//!     }
+//! }
+//! ```
+//!
+//! The names of the generated iteration methods are checked against the methods every map has:
+//!
+//! ```compile_fail
+//! # use fixed_typemap::decl_fixed_typemap;
+//! trait Named {}
+//! impl Named for u32 {}
+//!
+//! decl_fixed_typemap! {
+//!     #[fixed_typemap(iterable_traits(Named = "len"))]
+//!     struct Broken {
+//!         _: u32,
+//!     }
+//! }
+//! ```
+//!
+//! So are the names of accessors, which for entries declared as `_` come from their type:
+//!
+//! ```compile_fail
+//! # use fixed_typemap::decl_fixed_typemap;
+//! #[derive(Default)]
+//! struct Len(usize);
+//!
+//! decl_fixed_typemap! {
+//!     struct Broken {
+//!         // The accessor would be `len`.
+//!         _: Len,
+//!     }
+//! }
+//! ```
+//!
+//! And against each other, including the `get_as_` methods, which only use the last segment of the trait's path:
+//!
+//! ```compile_fail
+//! # use fixed_typemap::decl_fixed_typemap;
+//! mod audio { pub trait Describe {} }
+//! mod video { pub trait Describe {} }
+//! impl audio::Describe for u32 {}
+//! impl video::Describe for u32 {}
+//!
+//! decl_fixed_typemap! {
+//!     // Both traits would get `get_as_describe`.
+//!     #[fixed_typemap(iterable_traits(audio::Describe = "iter_audio", video::Describe = "iter_video"))]
+//!     struct Broken {
+//!         _: u32,
+//!     }
+//! }
+//! ```
+//!
+//! And the following can be used on fields:
+//!
+//! - `#[fixed_typemap(lazy)]`: don't run the initializer until the entry is first accessed.  The entry is still stored
+//!   inline, in a [core::cell::OnceCell], which means that maps with lazy entries aren't `Sync` unless they use
+//!   `const_new`.  Since lazy entries
+//!   aren't built with the rest of the map, their initializers can't refer to other entries and vice versa.  Inserting
+//!   a value or checking with `contains` doesn't build the entry, and inserting into one which hasn't been built returns
+//!   `None`.  If the field is named, it is the cell itself.
+//! - `#[fixed_typemap(boxed)]`: store the entry behind a `Box`, which keeps the map small when an entry is large.  The
+//!   entry is still keyed by its type and everything works through the box, but if the field is named, it is the `Box`
+//!   itself.  Can be combined with `lazy`, in which case nothing is allocated until first access.
+//! - `#[fixed_typemap(config = "key")]`: set this entry from `key` in `apply_config`, rather than the field name.  Just
+//!   `config` makes an entry declared as `_` configurable under its field name, and `config = false` opts a named entry
+//!   out.  Configurable entries must implement `FromStr`, with an error which implements `Display`.
+//! - `#[fixed_typemap(key = "key")]`: save this entry under `key`, rather than the [StableKey] of its key type.
+//! - `#[fixed_typemap(migrate = "path::to::function")]`: when loading data from an older version of the map, decode
+//!   this entry with `function(saved_version: u32, bytes: &[u8]) -> Result<Value, CodecError>`.
+//! - `#[fixed_typemap(persist = false)]`: leave this entry out of `save` and `load`.
+//! - `#[fixed_typemap(accessor = "name")]`: name the accessor methods `name` and `name_mut`, rather than after the
+//!   field.  This also gives accessors to named fields, which can be useful when the field is lazy or boxed.  Accessors
+//!   which collide with each other or with other methods of the map are errors:
+//!
+//! ```compile_fail
+//! # use fixed_typemap::decl_fixed_typemap;
+//! #[derive(Default)]
+//! struct Len(usize);
+//!
+//! decl_fixed_typemap! {
+//!     struct Broken {
+//!         // `len` is already a method of every map.
+//!         _: Len,
+//!     }
+//! }
+//! ```
+//!
+//! # Deriving
+//!
+//! The same maps can be built from an ordinary struct with `#[derive(FixedTypemap)]`, which keeps the struct visible to
+//! rustfmt and rust-analyzer.  Every field is an entry named after the field, and the struct-level attributes are the
+//! same as above.  Since the struct is written out, some things are spelled differently:
+//!
+//! - `#[fixed_typemap(default = "expr")]` on a field replaces `= expr`.
+//! - `#[fixed_typemap(marker = "Key")]` on a field replaces `Key =>`, with the field's type being the value.
+//! - Instead of `#[fixed_typemap(dynamic)]` on the struct, the dynamic section is a field of type [DynamicSection]
+//!   marked with `#[fixed_typemap(dynamic)]`.
+//! - `lazy` and `boxed` aren't supported, since they would change the type of the field.  `accessor`, `config`, `key`,
+//!   `migrate` and `persist` work as above.
+//!
+//! ```
+//! use fixed_typemap::{DynamicSection, FixedTypemap};
+//!
+//! #[derive(Default)]
+//! struct Volume(f32);
+//!
+//! struct MaxPlayers;
+//!
+//! #[derive(FixedTypemap)]
+//! struct Settings {
+//!     #[fixed_typemap(default = "Volume(0.5)")]
+//!     volume: Volume,
+//!     #[fixed_typemap(marker = "MaxPlayers", default = "16")]
+//!     max_players: u32,
+//!     #[fixed_typemap(dynamic)]
+//!     others: DynamicSection<Self>,
+//! }
+//!
+//! let mut settings = Settings::new();
+//! assert_eq!(*settings.get_infallible::<MaxPlayers>(), 16);
+//! settings.insert(String::from("extra")).unwrap();
+//! ```
+// So that generated code in this crate can refer to `::fixed_typemap`, as it does everywhere else.
+extern crate self as fixed_typemap;
+
 pub mod example;
+mod persist;
+mod registry;
 
-pub use fixed_typemap_internals::{InfallibleKey, IterableAs};
+pub use fixed_typemap_internals::{
+    CanInsert, ConfigError, ConfigErrors, DynTypemap, DynamicChange, DynamicZipVisitor,
+    DynamicZipVisitorMut, GettableAs, InfallibleKey, IterableAs, Typemap, TypemapKey,
+    TypemapVisitor, TypemapVisitorMut, TypemapZipVisitor, TypemapZipVisitorMut, Zipped,
+};
+
+#[doc(hidden)]
+pub use fixed_typemap_internals::HasDynamicSection;
 pub use fixed_typemap_macros::*;
+#[doc(hidden)]
+pub use persist::{read_saved, stable_keys_equal, write_saved, SavedMap};
+pub use persist::{Codec, CodecError, PersistError, StableKey};
+pub use registry::{RegistryError, TypeRegistry};
+
+/// The type of the field holding the dynamic section of a map using `#[derive(FixedTypemap)]`, which should be declared
+/// as `DynamicSection<Self>`.
+pub type DynamicSection<Map> =
+    std::collections::HashMap<std::any::TypeId, <Map as HasDynamicSection>::Cell>;
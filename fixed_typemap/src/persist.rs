@@ -0,0 +1,384 @@
+//! Saving and loading maps without serde: keys which are stable across compilations, and a small binary codec.
+use std::io::{Read, Write};
+
+/// A name for a key type which doesn't change between compilations, unlike its `TypeId`.
+///
+/// Maps with `#[fixed_typemap(persist(version = ...))]` save entries under the stable key of their key type, unless the
+/// entry gives one with `#[fixed_typemap(key = "...")]`.  Usually derived:
+///
+/// ```
+/// use fixed_typemap::StableKey;
+///
+/// #[derive(StableKey)]
+/// #[fixed_typemap(key = "app.graphics")]
+/// struct GraphicsSettings;
+///
+/// assert_eq!(GraphicsSettings::KEY, "app.graphics");
+/// ```
+///
+/// Without the attribute, the key is the path of the module the type is in followed by its name, e.g.
+/// `my_game::audio::Settings`, so that types with the same name in different modules don't share a key.  That changes if
+/// the type is moved or renamed, so give a key explicitly for data which has to outlive that.
+///
+/// Entries of a map can't share a key, which is checked when the map is compiled:
+///
+/// ```compile_fail
+/// use fixed_typemap::{decl_fixed_typemap, StableKey};
+///
+/// #[derive(StableKey, Default)]
+/// #[fixed_typemap(key = "settings")]
+/// struct AudioSettings(u32);
+///
+/// #[derive(StableKey, Default)]
+/// #[fixed_typemap(key = "settings")]
+/// struct VideoSettings(u32);
+/// # use fixed_typemap::{Codec, CodecError};
+/// # impl Codec for AudioSettings {
+/// #     fn encode(&self, out: &mut Vec<u8>) { self.0.encode(out) }
+/// #     fn decode(input: &mut &[u8]) -> Result<Self, CodecError> { u32::decode(input).map(Self) }
+/// # }
+/// # impl Codec for VideoSettings {
+/// #     fn encode(&self, out: &mut Vec<u8>) { self.0.encode(out) }
+/// #     fn decode(input: &mut &[u8]) -> Result<Self, CodecError> { u32::decode(input).map(Self) }
+/// # }
+///
+/// decl_fixed_typemap! {
+///     #[fixed_typemap(persist(version = 1))]
+///     struct Settings {
+///         _: AudioSettings,
+///         _: VideoSettings,
+///     }
+/// }
+/// ```
+pub trait StableKey {
+    const KEY: &'static str;
+}
+
+/// Why a value couldn't be decoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CodecError(String);
+
+impl CodecError {
+    pub fn new(message: impl Into<String>) -> Self {
+        CodecError(message.into())
+    }
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Encodes values to bytes for `save`, and decodes them for `load`.
+///
+/// Implemented for the primitives, `String`, `Vec` and `Option`.  Other types can be built from those by encoding each
+/// field in turn, and decoding them in the same order.
+pub trait Codec: Sized {
+    /// Append the encoded value to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decode a value from the start of `input`, advancing it past the bytes used.
+    fn decode(input: &mut &[u8]) -> Result<Self, CodecError>;
+
+    /// Decode a value which must use all of `input`.
+    fn decode_all(mut input: &[u8]) -> Result<Self, CodecError> {
+        let value = Self::decode(&mut input)?;
+        if !input.is_empty() {
+            return Err(CodecError::new(format!(
+                "{} bytes left over after decoding",
+                input.len()
+            )));
+        }
+        Ok(value)
+    }
+}
+
+/// Split `len` bytes off the front of `input`.
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], CodecError> {
+    if input.len() < len {
+        return Err(CodecError::new(format!(
+            "expected {} more bytes, but only {} are left",
+            len,
+            input.len()
+        )));
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+macro_rules! impl_codec_for_numbers {
+    ($($t: ty),*) => {
+        $(
+            impl Codec for $t {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+                    let bytes = take(input, std::mem::size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+impl_codec_for_numbers!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+// The sizes are platform-dependent, so these are always saved as 64 bits.
+impl Codec for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let value = u64::decode(input)?;
+        usize::try_from(value)
+            .map_err(|_| CodecError::new(format!("{} is too big for a usize", value)))
+    }
+}
+
+impl Codec for isize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as i64).encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let value = i64::decode(input)?;
+        isize::try_from(value)
+            .map_err(|_| CodecError::new(format!("{} is too big for an isize", value)))
+    }
+}
+
+impl Codec for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+        match u8::decode(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(CodecError::new(format!("{} isn't a bool", x))),
+        }
+    }
+}
+
+impl Codec for char {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u32).encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let value = u32::decode(input)?;
+        char::from_u32(value).ok_or_else(|| CodecError::new(format!("{:#x} isn't a char", value)))
+    }
+}
+
+impl Codec for () {
+    fn encode(&self, _out: &mut Vec<u8>) {}
+
+    fn decode(_input: &mut &[u8]) -> Result<Self, CodecError> {
+        Ok(())
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let len = usize::decode(input)?;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| CodecError::new(e.to_string()))
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for x in self.iter() {
+            x.encode(out);
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let len = usize::decode(input)?;
+        // Not trusting the length for the allocation, since the data may be corrupt.
+        let mut out = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            out.push(T::decode(input)?);
+        }
+        Ok(out)
+    }
+}
+
+impl<T: Codec> Codec for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(x) => {
+                out.push(1);
+                x.encode(out);
+            }
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, CodecError> {
+        match u8::decode(input)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(input)?)),
+            x => Err(CodecError::new(format!("{} isn't an option tag", x))),
+        }
+    }
+}
+
+/// Why `save` or `load` failed.
+#[derive(Debug)]
+pub enum PersistError {
+    Io(std::io::Error),
+    /// The data wasn't written by `save`.
+    BadFormat(String),
+    /// The data was saved by a newer version of the map, which this one can't know how to read.
+    NewerVersion {
+        saved: u32,
+        current: u32,
+    },
+    /// The entry saved under `key` couldn't be decoded or migrated.
+    Decode {
+        key: String,
+        error: CodecError,
+    },
+}
+
+impl PersistError {
+    #[doc(hidden)]
+    pub fn decode(key: &str, error: CodecError) -> Self {
+        PersistError::Decode {
+            key: key.to_string(),
+            error,
+        }
+    }
+}
+
+impl From<std::io::Error> for PersistError {
+    fn from(e: std::io::Error) -> Self {
+        PersistError::Io(e)
+    }
+}
+
+impl std::fmt::Display for PersistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PersistError::Io(e) => write!(f, "{}", e),
+            PersistError::BadFormat(why) => write!(f, "not a saved map: {}", why),
+            PersistError::NewerVersion { saved, current } => write!(
+                f,
+                "saved by version {} of the map, but this is version {}",
+                saved, current
+            ),
+            PersistError::Decode { key, error } => write!(f, "can't decode `{}`: {}", key, error),
+        }
+    }
+}
+
+impl std::error::Error for PersistError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PersistError::Io(e) => Some(e),
+            PersistError::Decode { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Whether two stable keys are the same, for generated code to check that a map's keys are distinct while compiling.
+#[doc(hidden)]
+pub const fn stable_keys_equal(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const MAGIC: &[u8; 4] = b"FXTM";
+
+/// The data read by `read_saved`, which generated `load` methods decode.
+#[doc(hidden)]
+pub struct SavedMap {
+    pub version: u32,
+    pub entries: Vec<(String, Vec<u8>)>,
+}
+
+/// Write the header and the entries as encoded by a generated `save`.
+///
+/// The format is the magic bytes, then the version and the number of entries, then each entry as its key and its
+/// encoded value.  Keys and values are both prefixed with their length, so entries which aren't known can be skipped.
+#[doc(hidden)]
+pub fn write_saved(
+    writer: &mut impl Write,
+    version: u32,
+    entries: &[(&str, Vec<u8>)],
+) -> Result<(), PersistError> {
+    let mut out = MAGIC.to_vec();
+    version.encode(&mut out);
+    entries.len().encode(&mut out);
+    for (key, value) in entries.iter() {
+        key.to_string().encode(&mut out);
+        value.len().encode(&mut out);
+        out.extend_from_slice(value);
+    }
+    writer.write_all(&out)?;
+    Ok(())
+}
+
+/// Read what `write_saved` wrote, checking that it isn't from a newer version than `current`.
+#[doc(hidden)]
+pub fn read_saved(reader: &mut impl Read, current: u32) -> Result<SavedMap, PersistError> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    let bad_format = |e: CodecError| PersistError::BadFormat(e.to_string());
+
+    let mut input = data.as_slice();
+    if take(&mut input, MAGIC.len()).map_err(bad_format)? != MAGIC {
+        return Err(PersistError::BadFormat("wrong magic bytes".to_string()));
+    }
+    let version = u32::decode(&mut input).map_err(bad_format)?;
+    if version > current {
+        return Err(PersistError::NewerVersion {
+            saved: version,
+            current,
+        });
+    }
+
+    let count = usize::decode(&mut input).map_err(bad_format)?;
+    let mut entries = Vec::with_capacity(count.min(input.len()));
+    for _ in 0..count {
+        let key = String::decode(&mut input).map_err(bad_format)?;
+        let len = usize::decode(&mut input).map_err(bad_format)?;
+        let value = take(&mut input, len).map_err(bad_format)?;
+        entries.push((key, value.to_vec()));
+    }
+    if !input.is_empty() {
+        return Err(PersistError::BadFormat(format!(
+            "{} bytes after the last entry",
+            input.len()
+        )));
+    }
+
+    Ok(SavedMap { version, entries })
+}
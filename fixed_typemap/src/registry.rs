@@ -0,0 +1,148 @@
+//! Building values for the dynamic section from names, for scripts and save files which can't name types statically.
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+use crate::CanInsert;
+
+/// Builds a value from `Args` and inserts it into a map, handing back the old value.
+type Inserter<Map, Args> =
+    Box<dyn Fn(&mut Map, Args) -> Result<Option<Box<dyn Any>>, ()> + Send + Sync>;
+
+struct Registration<Map, Args> {
+    type_id: TypeId,
+    insert: Inserter<Map, Args>,
+}
+
+/// Maps names to the types a map of type `Map` can hold, so that values can be built from `Args` and inserted by name.
+///
+/// A type can only be registered if the map could hold it, which for maps with `iterable_traits` means it implements
+/// every one of them.  The casts to those traits are captured when the type is registered, so values inserted through
+/// the registry are iterated like any other.
+///
+/// ```
+/// use fixed_typemap::{decl_fixed_typemap, TypeRegistry};
+///
+/// #[derive(Default)]
+/// struct Health(u32);
+///
+/// decl_fixed_typemap! {
+///     #[fixed_typemap(dynamic)]
+///     struct Entity {
+///         _: Health,
+///     }
+/// }
+///
+/// struct Speed(f32);
+///
+/// let mut registry = TypeRegistry::<Entity, f32>::new();
+/// registry.register("speed", Speed);
+///
+/// let mut entity = Entity::new();
+/// entity.insert_by_name(&registry, "speed", 2.5).unwrap();
+/// assert_eq!(entity.get::<Speed>().unwrap().0, 2.5);
+/// ```
+pub struct TypeRegistry<Map, Args = ()> {
+    types: HashMap<String, Registration<Map, Args>>,
+}
+
+/// Why inserting by name failed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RegistryError {
+    /// Nothing is registered under the name.
+    UnknownName(String),
+    /// The value was built, but the map couldn't hold it.  This is when `insert` would fail, e.g. because the map has
+    /// no dynamic section.
+    Rejected(String),
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RegistryError::UnknownName(name) => write!(f, "no type is registered as `{}`", name),
+            RegistryError::Rejected(name) => {
+                write!(f, "the map can't hold the type registered as `{}`", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl<Map, Args> TypeRegistry<Map, Args> {
+    /// An empty registry.
+    pub fn new() -> Self {
+        TypeRegistry {
+            types: HashMap::new(),
+        }
+    }
+
+    /// Register `K` under `name`, to be built by `construct`.
+    ///
+    /// Registering a name again replaces what it was registered as.
+    pub fn register<K: Any>(
+        &mut self,
+        name: impl Into<String>,
+        construct: impl Fn(Args) -> K + Send + Sync + 'static,
+    ) -> &mut Self
+    where
+        Map: CanInsert<K>,
+    {
+        let insert: Inserter<Map, Args> = Box::new(move |map, args| {
+            let old = map.try_insert(construct(args))?;
+            Ok(old.map(|x| Box::new(x) as Box<dyn Any>))
+        });
+        self.types.insert(
+            name.into(),
+            Registration {
+                type_id: TypeId::of::<K>(),
+                insert,
+            },
+        );
+        self
+    }
+
+    /// Register `K` under `name`, to be built with `Default` regardless of the arguments.
+    pub fn register_default<K: Any + Default>(&mut self, name: impl Into<String>) -> &mut Self
+    where
+        Map: CanInsert<K>,
+    {
+        self.register(name, |_| K::default())
+    }
+
+    /// Whether anything is registered under `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.types.contains_key(name)
+    }
+
+    /// The `TypeId` of the type registered under `name`, for looking it up with `get_by_type_id`.
+    pub fn type_id(&self, name: &str) -> Option<TypeId> {
+        self.types.get(name).map(|r| r.type_id)
+    }
+
+    /// Every registered name, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.types.keys().map(|x| x.as_str())
+    }
+
+    /// Build the type registered under `name` from `args`, and insert it into `map`.
+    ///
+    /// This is what the `insert_by_name` method of maps does.
+    pub fn insert(
+        &self,
+        map: &mut Map,
+        name: &str,
+        args: Args,
+    ) -> Result<Option<Box<dyn Any>>, RegistryError> {
+        let registration = self
+            .types
+            .get(name)
+            .ok_or_else(|| RegistryError::UnknownName(name.to_string()))?;
+        (registration.insert)(map, args).map_err(|_| RegistryError::Rejected(name.to_string()))
+    }
+}
+
+impl<Map, Args> Default for TypeRegistry<Map, Args> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -5,6 +5,13 @@
 //! on and use `fixed_typemap` as this is probably what you're looking for.
 #![no_std]
 
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::any::{Any, TypeId};
+
 pub use fixed_typemap_macros::*;
 
 /// A trait which represents the ability of a type to key a typemap infallibly.
@@ -13,12 +20,165 @@ pub use fixed_typemap_macros::*;
 /// can return it without having to wrap it in `Option` and the `get_infallible` method may be used to directly retrieve
 /// it.
 ///
+/// Usually the key is also the type of the stored value, but entries declared as `_: Key => Value` use a marker type as
+/// the key, in which case `Value` is what is stored.
+///
 /// You should never implement this trait yourself.
+///
+/// # Safety
+///
+/// The generated fallible getters assume that any type implementing this trait for a map is stored inline in that map.
+/// Only the proc macros can uphold that.
+#[diagnostic::on_unimplemented(
+    message = "type `{Self}` is not a fixed entry of map `{Map}`",
+    label = "not a fixed entry of `{Map}`",
+    note = "only types declared in the map can be used with `get_infallible`; use `get` for everything else"
+)]
 pub unsafe trait InfallibleKey<Map>: core::any::Any + Sized {
-    fn get_infallible(map: &Map) -> &Self;
-    fn get_infallible_mut(map: &mut Map) -> &mut Self;
+    /// The type of the value stored for this key.
+    type Value: core::any::Any;
+
+    fn get_infallible(map: &Map) -> &Self::Value;
+    fn get_infallible_mut(map: &mut Map) -> &mut Self::Value;
+
+    fn insert_infallible(map: &mut Map, value: Self::Value) -> Option<Self::Value>;
+}
+
+/// A key which stores a value of a different type.
+///
+/// This lets a map hold more than one value of the same type, e.g. two `u32` settings, by keying them with marker
+/// types.  Implement it for your markers to use them with the fallible `get_keyed` family of methods on generated maps:
+///
+/// ```ignore
+/// struct MaxPlayers;
+///
+/// impl TypemapKey for MaxPlayers {
+///     type Value = u32;
+/// }
+/// ```
+///
+/// Fixed entries keyed by markers are declared as `_: MaxPlayers => u32`, and don't need this trait to be used through
+/// [InfallibleKey].
+pub trait TypemapKey: core::any::Any {
+    type Value: core::any::Any;
+}
+
+/// A trait implemented by every generated map, so that code can be generic over maps declared elsewhere.
+///
+/// These forward to the inherent methods of the same names, with one difference: a map which iterates by traits can
+/// only hold types implementing those traits, and since that can't be expressed here, `insert` through this trait only
+/// works for types already in the map when the map has `iterable_traits`.
+pub trait Typemap {
+    /// Try to get a value from the typemap.
+    fn get<K: core::any::Any>(&self) -> Option<&K>;
+
+    /// Try to get a mutable reference to a value in the typemap.
+    fn get_mut<K: core::any::Any>(&mut self) -> Option<&mut K>;
+
+    /// Try to insert into the typemap, returning the old value if there was one.
+    #[allow(clippy::result_unit_err)]
+    fn insert<K: core::any::Any>(&mut self, value: K) -> Result<Option<K>, ()>;
 
-    fn insert_infallible(map: &mut Map, value: Self) -> Option<Self>;
+    /// Whether the typemap contains a value of the given type.
+    fn contains<K: core::any::Any>(&self) -> bool {
+        self.get::<K>().is_some()
+    }
+
+    /// The number of values in the typemap, including every fixed entry.
+    fn len(&self) -> usize;
+
+    /// Whether the typemap contains no values at all.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Implemented by a map for every type it could hold, so that generic code can insert values of a type parameter.
+///
+/// Unlike [Typemap::insert], this is only implemented for types which implement every trait the map iterates by, so it
+/// also works for new types in maps with `iterable_traits`.  Maps without a dynamic section implement it for every type,
+/// but only succeed for the ones they hold.
+pub trait CanInsert<K: Any> {
+    /// Try to insert into the typemap, as the inherent `insert` does.
+    #[allow(clippy::result_unit_err)]
+    fn try_insert(&mut self, value: K) -> Result<Option<K>, ()>;
+}
+
+/// An object-safe view of a typemap, which lets maps declared by different crates be stored behind one pointer.
+///
+/// Everything here works with `TypeId` and `dyn Any`.  Typed access is provided by methods on `dyn DynTypemap`
+/// itself, so that `Vec<Box<dyn DynTypemap>>` can be used like:
+///
+/// ```ignore
+/// let time: Option<&Time> = maps[0].get::<Time>();
+/// ```
+///
+/// Keys are as in the rest of the crate: for entries declared as `Key => Value` the `TypeId` is that of the key, and the
+/// returned value is the value.
+pub trait DynTypemap {
+    /// Get the value stored under the given key.
+    fn get_dyn(&self, key: TypeId) -> Option<&dyn Any>;
+
+    /// Get a mutable reference to the value stored under the given key.
+    fn get_dyn_mut(&mut self, key: TypeId) -> Option<&mut dyn Any>;
+
+    /// Insert a boxed value, keyed by its own type.
+    ///
+    /// Returns the old value if there was one.  If the value can't be inserted, it is handed back as the error.  Beyond
+    /// the reasons `insert` can fail, maps which iterate by traits can't hold new boxed values, since there's no way to
+    /// cast them to the traits.
+    fn insert_boxed(&mut self, value: Box<dyn Any>) -> Result<Option<Box<dyn Any>>, Box<dyn Any>>;
+
+    /// The keys of every value in the map.
+    fn type_ids(&self) -> Vec<TypeId>;
+}
+
+macro_rules! impl_dyn_typemap_methods {
+    ($($t: ty),*) => {
+        $(
+            impl $t {
+                /// Try to get a value from the typemap.
+                pub fn get<K: Any>(&self) -> Option<&K> {
+                    self.get_dyn(TypeId::of::<K>())?.downcast_ref()
+                }
+
+                /// Try to get a mutable reference to a value in the typemap.
+                pub fn get_mut<K: Any>(&mut self) -> Option<&mut K> {
+                    self.get_dyn_mut(TypeId::of::<K>())?.downcast_mut()
+                }
+
+                /// Whether the typemap contains a value of the given type.
+                pub fn contains<K: Any>(&self) -> bool {
+                    self.get::<K>().is_some()
+                }
+
+                /// Try to insert into the typemap, returning the old value if there was one or the new one if it
+                /// couldn't be inserted.
+                pub fn insert<K: Any>(&mut self, value: K) -> Result<Option<K>, K> {
+                    // The box only ever holds a K, going in or coming out.
+                    match self.insert_boxed(Box::new(value)) {
+                        Ok(old) => Ok(old.and_then(|x| x.downcast().ok()).map(|x| *x)),
+                        Err(new) => Err(*new.downcast().ok().unwrap()),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_dyn_typemap_methods!(
+    dyn DynTypemap,
+    dyn DynTypemap + Send,
+    dyn DynTypemap + Send + Sync
+);
+
+/// Names the type which holds values in the dynamic section of a map, so that `DynamicSection<Self>` can be written in
+/// derived structs.
+///
+/// You should never implement this trait yourself.
+#[doc(hidden)]
+pub trait HasDynamicSection {
+    type Cell;
 }
 
 /// A trait which represents the ability to iterate over a typemap with a specific trait object tuype.
@@ -38,3 +198,221 @@ pub trait IterableAs<'a, Map>: 'a {
     /// Get a mutable iterator for the specified trait.
     fn iter_mut_as(map: &'a mut Map) -> Self::IterMut;
 }
+
+/// A trait which represents the ability to get a single entry of a typemap as a specific trait object type, by the
+/// `TypeId` of its key.
+///
+/// This is the counterpart to [IterableAs] for code which only knows which entry it wants at runtime, e.g. a plugin
+/// registry.  As with that trait, use it like:
+///
+/// `<dyn MyTrait>::get_as(&mymap, type_id)`.
+pub trait GettableAs<'a, Map>: 'a {
+    /// Get the entry with the given key as this trait object.
+    fn get_as(map: &'a Map, key: TypeId) -> Option<&'a Self>;
+
+    /// Get a mutable reference to the entry with the given key as this trait object.
+    fn get_as_mut(map: &'a mut Map, key: TypeId) -> Option<&'a mut Self>;
+}
+
+/// A problem with one `(key, value)` pair given to the `apply_config` method of a map.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConfigError {
+    /// The key doesn't name an entry which can be configured.
+    UnknownKey { key: String },
+    /// The value couldn't be parsed for the entry, for the reason in `message`.
+    Parse {
+        key: String,
+        value: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    #[doc(hidden)]
+    pub fn unknown_key(key: &str) -> Self {
+        ConfigError::UnknownKey {
+            key: key.to_string(),
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn parse<E: core::fmt::Display>(key: &str, value: &str, error: &E) -> Self {
+        ConfigError::Parse {
+            key: key.to_string(),
+            value: value.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    /// The key this error is about.
+    pub fn key(&self) -> &str {
+        match self {
+            ConfigError::UnknownKey { key } | ConfigError::Parse { key, .. } => key,
+        }
+    }
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ConfigError::UnknownKey { key } => write!(f, "unknown key `{}`", key),
+            ConfigError::Parse {
+                key,
+                value,
+                message,
+            } => write!(f, "can't parse `{}` for `{}`: {}", value, key, message),
+        }
+    }
+}
+
+impl core::error::Error for ConfigError {}
+
+/// Every problem found by the `apply_config` method of a map, in the order the pairs were given.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConfigErrors {
+    errors: Vec<ConfigError>,
+}
+
+impl ConfigErrors {
+    #[doc(hidden)]
+    pub fn push(&mut self, error: ConfigError) {
+        self.errors.push(error);
+    }
+
+    #[doc(hidden)]
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// The individual errors.
+    pub fn errors(&self) -> &[ConfigError] {
+        &self.errors
+    }
+}
+
+impl IntoIterator for ConfigErrors {
+    type Item = ConfigError;
+    type IntoIter = alloc::vec::IntoIter<ConfigError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.errors.into_iter()
+    }
+}
+
+impl core::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for (i, e) in self.errors.iter().enumerate() {
+            if i != 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for ConfigErrors {}
+
+/// Visits the fixed entries of a map through its `visit` method, one call per entry, without trait objects.
+///
+/// A visitor implements this for every value type it can handle, usually generically with the bound it needs.  Since
+/// every call is monomorphized, the bound can be a trait which isn't object safe:
+///
+/// ```ignore
+/// struct HashEntries<H>(H);
+///
+/// impl<H: Hasher, T: Hash> TypemapVisitor<T> for HashEntries<H> {
+///     fn visit<K: Any>(&mut self, value: &T) {
+///         value.hash(&mut self.0);
+///     }
+/// }
+/// ```
+///
+/// `K` is the key of the entry, which for entries declared as `Key => Value` isn't the type of the value.
+pub trait TypemapVisitor<T: ?Sized> {
+    fn visit<K: Any>(&mut self, value: &T);
+}
+
+/// Like [TypemapVisitor], for the `visit_mut` method of maps.
+pub trait TypemapVisitorMut<T: ?Sized> {
+    fn visit_mut<K: Any>(&mut self, value: &mut T);
+}
+
+/// Visits the matching fixed entries of two maps of the same type through their `zip_visit` method.
+///
+/// As with [TypemapVisitor], implement this for every value type the visitor can handle.  `left` is from the map
+/// `zip_visit` was called on, and `right` from the other one.
+pub trait TypemapZipVisitor<T: ?Sized> {
+    fn visit<K: Any>(&mut self, left: &T, right: &T);
+}
+
+/// Like [TypemapZipVisitor], for the `zip_visit_mut` method of maps.
+pub trait TypemapZipVisitorMut<T: ?Sized> {
+    fn visit_mut<K: Any>(&mut self, left: &mut T, right: &mut T);
+}
+
+/// Which of two maps have a value in their dynamic sections, as seen by a [DynamicZipVisitor].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Zipped<T> {
+    Both(T, T),
+    LeftOnly(T),
+    RightOnly(T),
+}
+
+/// Visits the dynamic sections of two maps through their `zip_visit` method, which is only required of visitors for
+/// maps with a dynamic section.
+///
+/// Since the types in the dynamic section aren't known statically, values are given as `dyn Any` along with their key.
+pub trait DynamicZipVisitor {
+    fn visit_dynamic(&mut self, key: TypeId, entry: Zipped<&dyn Any>);
+}
+
+/// Like [DynamicZipVisitor], for the `zip_visit_mut` method of maps.
+pub trait DynamicZipVisitorMut {
+    fn visit_dynamic_mut(&mut self, key: TypeId, entry: Zipped<&mut dyn Any>);
+}
+
+/// A change to the dynamic section of a map, in the diffs of maps with `#[fixed_typemap(diff)]`.
+///
+/// Diffs hold the new values in the map's cell type, and hand them out as `&dyn Any`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DynamicChange<T> {
+    /// The value is new.
+    Inserted(T),
+    /// The value was there before but is different.
+    Changed(T),
+    /// The value is gone.
+    Removed,
+}
+
+impl<T> DynamicChange<T> {
+    /// Borrow the new value, if there is one.
+    pub fn as_ref(&self) -> DynamicChange<&T> {
+        match self {
+            DynamicChange::Inserted(x) => DynamicChange::Inserted(x),
+            DynamicChange::Changed(x) => DynamicChange::Changed(x),
+            DynamicChange::Removed => DynamicChange::Removed,
+        }
+    }
+
+    /// Convert the new value, if there is one.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> DynamicChange<U> {
+        match self {
+            DynamicChange::Inserted(x) => DynamicChange::Inserted(f(x)),
+            DynamicChange::Changed(x) => DynamicChange::Changed(f(x)),
+            DynamicChange::Removed => DynamicChange::Removed,
+        }
+    }
+
+    /// The new value, or `None` if it was removed.
+    pub fn value(self) -> Option<T> {
+        match self {
+            DynamicChange::Inserted(x) | DynamicChange::Changed(x) => Some(x),
+            DynamicChange::Removed => None,
+        }
+    }
+}
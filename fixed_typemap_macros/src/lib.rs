@@ -11,21 +11,209 @@ use syn::{
     parse_quote, Token,
 };
 
-#[derive(Debug, darling::FromAttributes)]
+#[derive(Debug, Default, darling::FromAttributes)]
 #[darling(attributes(fixed_typemap))]
 struct MapAttributes {
     #[darling(default)]
     dynamic: bool,
     #[darling(default)]
     iterable_traits: std::collections::HashMap<syn::Path, syn::Ident>,
+    #[darling(default)]
+    fallible: Option<FallibleAttributes>,
+    #[darling(default)]
+    lazy_iteration: LazyIteration,
+    #[darling(default)]
+    const_new: bool,
+    /// The name of the type holding values in the dynamic section, `{Name}Cell` by default.
+    #[darling(default)]
+    cell_name: Option<syn::Ident>,
+    /// The name of the enum with a variant per fixed entry, `{Name}Key` by default.
+    #[darling(default)]
+    key_enum: Option<syn::Ident>,
+    /// Generate `apply_config`.
+    #[darling(default)]
+    config: bool,
+    /// Generate `save` and `load`.
+    #[darling(default)]
+    persist: Option<PersistAttributes>,
+    /// Generate `diff` and `apply`.
+    #[darling(default)]
+    diff: bool,
+    /// The name of the type returned by `diff`, `{Name}Diff` by default.
+    #[darling(default)]
+    diff_name: Option<syn::Ident>,
+    /// The path to the `fixed_typemap` crate, for facade crates which re-export it.
+    #[darling(default, rename = "crate")]
+    crate_path: Option<syn::Path>,
+}
+
+/// What trait iteration does with lazy entries which haven't been initialized yet.
+#[derive(Debug, Default, Eq, PartialEq)]
+enum LazyIteration {
+    /// Initialize them, so that iteration always sees every fixed entry.
+    #[default]
+    Init,
+    /// Leave them alone and don't yield them.
+    Skip,
+}
+
+impl darling::FromMeta for LazyIteration {
+    fn from_string(value: &str) -> darling::Result<Self> {
+        match value {
+            "init" => Ok(LazyIteration::Init),
+            "skip" => Ok(LazyIteration::Skip),
+            _ => Err(darling::Error::unknown_value(value)),
+        }
+    }
+}
+
+#[derive(Debug, Default, darling::FromAttributes)]
+#[darling(attributes(fixed_typemap))]
+struct EntryAttributes {
+    #[darling(default)]
+    lazy: bool,
+    #[darling(default)]
+    boxed: bool,
+    /// The name of the accessor methods, for entries declared as `_` or which want accessors anyway.
+    #[darling(default)]
+    accessor: Option<syn::Ident>,
+    #[darling(default)]
+    config: Option<EntryConfig>,
+    /// The stable key to save the entry under, instead of the `StableKey` of its key type.
+    #[darling(default)]
+    key: Option<String>,
+    /// A function which converts the entry from data saved by an older version of the map.
+    #[darling(default)]
+    migrate: Option<syn::Path>,
+    /// `persist = false` leaves the entry out of saved data.
+    #[darling(default)]
+    persist: Option<bool>,
+}
+
+/// Whether an entry can be set by `apply_config`, and under which key.
+#[derive(Debug, Eq, PartialEq)]
+enum EntryConfig {
+    /// `config`, for entries declared as `_`: use the name of the field.
+    Include,
+    /// `config = "key"`.
+    Rename(String),
+    /// `config = false`.
+    Skip,
+}
+
+impl darling::FromMeta for EntryConfig {
+    fn from_word() -> darling::Result<Self> {
+        Ok(EntryConfig::Include)
+    }
+
+    fn from_string(value: &str) -> darling::Result<Self> {
+        Ok(EntryConfig::Rename(value.to_string()))
+    }
+
+    fn from_bool(value: bool) -> darling::Result<Self> {
+        Ok(if value {
+            EntryConfig::Include
+        } else {
+            EntryConfig::Skip
+        })
+    }
+}
+
+/// The attributes on the fields of a struct using the derive.
+#[derive(Debug, Default, darling::FromAttributes)]
+#[darling(attributes(fixed_typemap))]
+struct DeriveEntryAttributes {
+    /// Replaces `= expr`.
+    #[darling(default)]
+    default: Option<ParsedExpr>,
+    /// Replaces `Key =>`.
+    #[darling(default)]
+    marker: Option<ParsedType>,
+    /// Marks the field holding the dynamic section.
+    #[darling(default)]
+    dynamic: bool,
+    #[darling(default)]
+    accessor: Option<syn::Ident>,
+    #[darling(default)]
+    config: Option<EntryConfig>,
+    #[darling(default)]
+    key: Option<String>,
+    #[darling(default)]
+    migrate: Option<syn::Path>,
+    #[darling(default)]
+    persist: Option<bool>,
+    // These two are only here so that we can say why they aren't supported.
+    #[darling(default)]
+    lazy: bool,
+    #[darling(default)]
+    boxed: bool,
+}
+
+#[derive(Debug, darling::FromMeta)]
+struct FallibleAttributes {
+    error: ParsedType,
+}
+
+#[derive(Debug, darling::FromMeta)]
+struct PersistAttributes {
+    /// The version written to saved data, which is handed to migration functions when loading older data.
+    version: u32,
+}
+
+/// The attributes of a type using `#[derive(StableKey)]`.
+#[derive(Debug, Default, darling::FromAttributes)]
+#[darling(attributes(fixed_typemap))]
+struct StableKeyAttributes {
+    /// The key, which is the name of the type by default.
+    #[darling(default)]
+    key: Option<String>,
+    #[darling(default, rename = "crate")]
+    crate_path: Option<syn::Path>,
+}
+
+/// A type, given to an attribute as a string so that it's valid meta syntax.
+#[derive(Debug)]
+struct ParsedType(syn::Type);
+
+impl darling::FromMeta for ParsedType {
+    fn from_value(value: &syn::Lit) -> darling::Result<Self> {
+        match value {
+            syn::Lit::Str(s) => Ok(ParsedType(s.parse()?)),
+            _ => Err(darling::Error::unexpected_lit_type(value)),
+        }
+    }
+}
+
+/// An expression, given to an attribute as a string so that it's valid meta syntax.
+#[derive(Debug)]
+struct ParsedExpr(syn::Expr);
+
+impl darling::FromMeta for ParsedExpr {
+    fn from_value(value: &syn::Lit) -> darling::Result<Self> {
+        match value {
+            syn::Lit::Str(s) => Ok(ParsedExpr(s.parse()?)),
+            _ => Err(darling::Error::unexpected_lit_type(value)),
+        }
+    }
 }
 
 struct MapEntry {
     attrs: Vec<syn::Attribute>,
+    parsed_attrs: EntryAttributes,
     vis: syn::Visibility,
     name: Option<syn::Ident>,
+    /// Whether the entry was declared as `_`, in which case the name is chosen by `ensure_names`.
+    anonymous: bool,
     key_type: syn::Type,
+    /// Set if the entry is declared as `Key => Value`.
+    value_type: Option<syn::Type>,
     initializer: syn::Expr,
+    /// Whether the initializer was written by the user, rather than being `Default::default()`.
+    explicit_initializer: bool,
+    /// Errors from parsing `parsed_attrs`, which are held until the whole map is parsed so they can all be reported.
+    attr_errors: Option<syn::Error>,
+    /// The `cfg` attributes of the entry, which go on everything generated for it.
+    cfgs: Vec<syn::Attribute>,
 }
 
 struct Map {
@@ -36,7 +224,13 @@ struct Map {
     entries: Vec<MapEntry>,
     dynamic_field_name: proc_macro2::Ident,
     dynamic_cell_name: syn::Ident,
+    key_enum_name: syn::Ident,
+    diff_type_name: syn::Ident,
     additional_key_constraints: Vec<syn::Path>,
+    /// Errors from parsing `parsed_attrs`, as for entries.
+    attr_errors: Option<syn::Error>,
+    /// Where the generated code finds the traits it implements.
+    crate_path: syn::Path,
 }
 
 /// Build and return a macro snippet which uses unreachable for a fast unwrap.
@@ -45,38 +239,240 @@ struct Map {
 fn fast_unwrap(expr: TokenStream2) -> TokenStream2 {
     quote!({
         match #expr {
-            Some(x) => x,
-            None => unsafe { ::core::hint::unreachable_unchecked() },
+            ::core::option::Option::Some(x) => x,
+            ::core::option::Option::None => unsafe { ::core::hint::unreachable_unchecked() },
         }
     })
 }
 
+/// Convert a darling error to a syn one, keeping the span of every error it contains.
+///
+/// Errors without a span are pointed at `fallback`.  darling only exposes spans through `write_errors`, so we get them
+/// from the first token of what that writes.
+fn darling_to_syn(err: darling::Error, fallback: proc_macro2::Span) -> syn::Error {
+    let mut result: Option<syn::Error> = None;
+
+    for e in err.flatten() {
+        let msg = e.to_string();
+        let span = if e.has_span() {
+            e.write_errors()
+                .into_iter()
+                .next()
+                .map_or(fallback, |x| x.span())
+        } else {
+            fallback
+        };
+        let converted = syn::Error::new(span, msg);
+        match result.as_mut() {
+            Some(r) => r.combine(converted),
+            None => result = Some(converted),
+        }
+    }
+
+    result.unwrap_or_else(|| syn::Error::new(fallback, "invalid attribute"))
+}
+
+/// Parse our attributes from a list, returning the defaults and the error if they're invalid.
+fn parse_attributes<T: FromAttributes + Default>(
+    attrs: &[syn::Attribute],
+    fallback: proc_macro2::Span,
+) -> (T, Option<syn::Error>) {
+    match T::from_attributes(attrs) {
+        Ok(x) => (x, None),
+        Err(e) => {
+            // Point at our attributes if we can, since that's where the problem is.
+            let ours = attrs.iter().find(|a| a.path.is_ident("fixed_typemap"));
+            let err = match ours {
+                Some(a) => darling_to_syn(e, syn::spanned::Spanned::span(a)),
+                None => darling_to_syn(e, fallback),
+            };
+            (T::default(), Some(err))
+        }
+    }
+}
+
+/// Combine a list of errors into one, or succeed if there are none.
+fn combine_errors(errors: impl IntoIterator<Item = syn::Error>) -> syn::Result<()> {
+    let mut result: Option<syn::Error> = None;
+    for e in errors {
+        match result.as_mut() {
+            Some(r) => r.combine(e),
+            None => result = Some(e),
+        }
+    }
+
+    match result {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Convert a type name such as `IntegralId` to `integral_id`, for building method names.
+///
+/// Acronyms are one word, which ends before a capital followed by a lowercase letter, so `HTTPServer` is `http_server`.
+fn to_snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut out = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if !c.is_uppercase() {
+            out.push(*c);
+            continue;
+        }
+
+        let prev = i.checked_sub(1).map(|p| chars[p]);
+        let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+        let starts_word = match prev {
+            Some(p) if p.is_lowercase() || p.is_ascii_digit() => true,
+            Some(p) if p.is_uppercase() => next_lower,
+            _ => false,
+        };
+        if starts_word {
+            out.push('_');
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+/// Convert a field name such as `sound_plugin` to `SoundPlugin`, for building variant names.
+fn to_camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// The name of the `get_as_` method for a trait, built from the last segment of its path.
+fn get_as_method_name(trait_path: &syn::Path) -> syn::Ident {
+    let last = &trait_path.segments.last().unwrap().ident;
+    quote::format_ident!("get_as_{}", to_snake_case(&last.to_string()))
+}
+
+/// Find the attributes which decide whether an entry exists: `cfg`, and `cfg_attr` which expands to `cfg`.
+///
+/// Other attributes inside `cfg_attr` are for the field, so they're dropped and a `cfg_attr` with nothing left is
+/// dropped entirely.
+fn cfg_attributes(attrs: &[syn::Attribute]) -> Vec<syn::Attribute> {
+    fn keep_cfgs(meta: &syn::Meta) -> Option<syn::Meta> {
+        if meta.path().is_ident("cfg") {
+            return Some(meta.clone());
+        }
+        if !meta.path().is_ident("cfg_attr") {
+            return None;
+        }
+
+        let list = match meta {
+            syn::Meta::List(l) => l,
+            _ => return None,
+        };
+        let mut nested = list.nested.iter();
+        let predicate = nested.next()?;
+        let kept = nested
+            .filter_map(|n| match n {
+                syn::NestedMeta::Meta(m) => keep_cfgs(m),
+                syn::NestedMeta::Lit(_) => None,
+            })
+            .collect::<Vec<_>>();
+        if kept.is_empty() {
+            return None;
+        }
+        Some(parse_quote!(cfg_attr(#predicate, #(#kept),*)))
+    }
+
+    attrs
+        .iter()
+        .filter(|a| a.path.is_ident("cfg") || a.path.is_ident("cfg_attr"))
+        .filter_map(|a| a.parse_meta().ok())
+        .filter_map(|m| keep_cfgs(&m))
+        .map(|m| parse_quote!(#[#m]))
+        .collect()
+}
+
+/// The condition under which attributes from `cfg_attributes` keep an item, for building the opposite condition.
+fn cfg_predicate(cfgs: &[syn::Attribute]) -> TokenStream2 {
+    fn predicate(meta: &syn::Meta) -> TokenStream2 {
+        let nested = match meta {
+            syn::Meta::List(l) => l.nested.iter().collect::<Vec<_>>(),
+            _ => return quote!(all()),
+        };
+        if meta.path().is_ident("cfg") {
+            return quote!(#(#nested),*);
+        }
+
+        // A `cfg_attr` only applies its `cfg`s if its own predicate holds.
+        let condition = nested[0];
+        let applied = nested[1..].iter().map(|n| match n {
+            syn::NestedMeta::Meta(m) => predicate(m),
+            syn::NestedMeta::Lit(_) => quote!(all()),
+        });
+        quote!(any(not(#condition), all(#(#applied),*)))
+    }
+
+    let predicates = cfgs
+        .iter()
+        .filter_map(|a| a.parse_meta().ok())
+        .map(|m| predicate(&m));
+    quote!(all(#(#predicates),*))
+}
+
+/// Remove all `fixed_typemap` attributes, which are for us and shouldn't be forwarded.
+fn strip_our_attributes(attrs: &mut Vec<syn::Attribute>) {
+    attrs.retain(|i| {
+        for seg in i.path.segments.iter() {
+            if seg.ident == "fixed_typemap" {
+                return false;
+            }
+        }
+        true
+    });
+}
+
 impl Parse for MapEntry {
     fn parse(stream: ParseStream) -> PResult<Self> {
-        let attrs = syn::Attribute::parse_outer(stream)?;
+        let mut attrs = syn::Attribute::parse_outer(stream)?;
+        let (parsed_attrs, attr_errors) = parse_attributes(&attrs, stream.span());
+        strip_our_attributes(&mut attrs);
+        let cfgs = cfg_attributes(&attrs);
         let vis: syn::Visibility = stream.parse()?;
 
-        let name;
-        if stream.peek(Token![_]) {
+        let name = if stream.peek(Token![_]) {
             stream.parse::<Token![_]>()?;
-            name = None;
+            None
         } else {
-            name = Some(stream.parse()?);
-        }
+            Some(stream.parse()?)
+        };
         stream.parse::<Token![:]>()?;
         let key_type: syn::Type = stream.parse()?;
 
-        let mut initializer = syn::parse_quote!(Default::default());
-        if stream.peek(Token![=]) {
+        let mut value_type = None;
+        if stream.peek(Token![=>]) {
+            stream.parse::<Token![=>]>()?;
+            value_type = Some(stream.parse()?);
+        }
+
+        let mut initializer = syn::parse_quote!(::core::default::Default::default());
+        let explicit_initializer = stream.peek(Token![=]);
+        if explicit_initializer {
             stream.parse::<Token![=]>()?;
             initializer = stream.parse()?;
         }
         Ok(MapEntry {
             attrs,
+            parsed_attrs,
             vis,
             key_type,
+            value_type,
+            anonymous: name.is_none(),
             name,
             initializer,
+            explicit_initializer,
+            attr_errors,
+            cfgs,
         })
     }
 }
@@ -84,18 +480,11 @@ impl Parse for MapEntry {
 impl Parse for Map {
     fn parse(stream: ParseStream) -> PResult<Self> {
         let mut forwarded_attrs = stream.call(syn::Attribute::parse_outer)?;
-        let parsed_attrs = MapAttributes::from_attributes(&forwarded_attrs)
-            .map_err(|e| stream.error(e.to_string()))?;
+        let (parsed_attrs, attr_errors): (MapAttributes, _) =
+            parse_attributes(&forwarded_attrs, stream.span());
 
         // We must now get rid of all of the fixed_typemap attributes.
-        forwarded_attrs.retain(|i| {
-            for seg in i.path.segments.iter() {
-                if seg.ident == "fixed_typemap" {
-                    return false;
-                }
-            }
-            true
-        });
+        strip_our_attributes(&mut forwarded_attrs);
 
         let vis = stream.parse()?;
         stream.parse::<Token![struct]>()?;
@@ -108,48 +497,234 @@ impl Parse for Map {
             .into_iter()
             .collect();
 
-        let additional_key_constraints = parsed_attrs
-            .iterable_traits
-            .keys()
-            .map(|x| x.clone())
-            .collect();
+        let additional_key_constraints = key_constraints(&parsed_attrs);
+        let crate_path = crate_path(&parsed_attrs);
+        let dynamic_cell_name = cell_name(&parsed_attrs, &name);
+        let key_enum_name = key_enum_name(&parsed_attrs, &name);
+        let diff_type_name = diff_name(&parsed_attrs, &name);
 
         Ok(Map {
             forwarded_attrs,
             parsed_attrs,
             vis,
-            dynamic_cell_name: quote::format_ident!("{}Cell", name),
+            dynamic_cell_name,
+            key_enum_name,
+            diff_type_name,
             name,
             entries,
             // This is set later, in ensure_names, but we need a dumy value for now.
             dynamic_field_name: quote::format_ident!("not_set"),
             additional_key_constraints,
+            attr_errors,
+            crate_path,
         })
     }
 }
 
+/// The traits every value in the map has to implement: the ones it iterates by, and those needed to diff it.
+fn key_constraints(attrs: &MapAttributes) -> Vec<syn::Path> {
+    let mut constraints = attrs.iterable_traits.keys().cloned().collect::<Vec<_>>();
+    if attrs.diff {
+        constraints.push(parse_quote!(::core::cmp::PartialEq));
+        constraints.push(parse_quote!(::core::clone::Clone));
+    }
+    constraints
+}
+
+/// The path to `fixed_typemap` to use in generated code: the `crate` option, or `::fixed_typemap`.
+fn crate_path(attrs: &MapAttributes) -> syn::Path {
+    attrs
+        .crate_path
+        .clone()
+        .unwrap_or_else(|| parse_quote!(::fixed_typemap))
+}
+
+/// The name of the cell type for the dynamic section: the `cell_name` option, or `{Name}Cell`.
+fn cell_name(attrs: &MapAttributes, map_name: &syn::Ident) -> syn::Ident {
+    attrs
+        .cell_name
+        .clone()
+        .unwrap_or_else(|| quote::format_ident!("{}Cell", map_name))
+}
+
+/// The name of the enum of fixed entries: the `key_enum` option, or `{Name}Key`.
+fn key_enum_name(attrs: &MapAttributes, map_name: &syn::Ident) -> syn::Ident {
+    attrs
+        .key_enum
+        .clone()
+        .unwrap_or_else(|| quote::format_ident!("{}Key", map_name))
+}
+
+/// The name of the type holding the changes between two maps, from `diff`: the `diff_name` option, or `{Name}Diff`.
+fn diff_name(attrs: &MapAttributes, map_name: &syn::Ident) -> syn::Ident {
+    attrs
+        .diff_name
+        .clone()
+        .unwrap_or_else(|| quote::format_ident!("{}Diff", map_name))
+}
+
+/// Build a map from a struct using `#[derive(FixedTypemap)]`.
+///
+/// Every field is an entry named after the field, except the one marked `#[fixed_typemap(dynamic)]`, which becomes the
+/// dynamic section.
+fn map_from_derive(input: syn::DeriveInput) -> syn::Result<Map> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "typemaps can't have generics or lifetime parameters",
+        ));
+    }
+
+    let fields = match input.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => fields.named,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "`FixedTypemap` can only be derived for structs with named fields",
+            ))
+        }
+    };
+
+    let (mut parsed_attrs, attr_errors): (MapAttributes, _) =
+        parse_attributes(&input.attrs, input.ident.span());
+    if parsed_attrs.dynamic {
+        return Err(syn::Error::new(
+            input.ident.span(),
+            "derived maps get a dynamic section by marking a field of type `DynamicSection<Self>` with `#[fixed_typemap(dynamic)]`",
+        ));
+    }
+
+    let mut entries = vec![];
+    let mut dynamic_field_name = None;
+
+    for f in fields {
+        let name = f.ident.unwrap();
+        let (attrs, entry_attr_errors): (DeriveEntryAttributes, _) =
+            parse_attributes(&f.attrs, name.span());
+
+        if attrs.lazy || attrs.boxed {
+            return Err(syn::Error::new(
+                name.span(),
+                "`lazy` and `boxed` change the type of the field, so they're only supported by `decl_fixed_typemap!`",
+            ));
+        }
+
+        if attrs.dynamic {
+            if dynamic_field_name.is_some() {
+                return Err(syn::Error::new(
+                    name.span(),
+                    "only one field can hold the dynamic section",
+                ));
+            }
+            dynamic_field_name = Some(name);
+            continue;
+        }
+
+        let (key_type, value_type) = match attrs.marker {
+            Some(ParsedType(marker)) => (marker, Some(f.ty)),
+            None => (f.ty, None),
+        };
+        let explicit_initializer = attrs.default.is_some();
+        let initializer = attrs
+            .default
+            .map(|x| x.0)
+            .unwrap_or_else(|| syn::parse_quote!(::core::default::Default::default()));
+
+        entries.push(MapEntry {
+            attrs: vec![],
+            parsed_attrs: EntryAttributes {
+                accessor: attrs.accessor,
+                config: attrs.config,
+                key: attrs.key,
+                migrate: attrs.migrate,
+                persist: attrs.persist,
+                ..Default::default()
+            },
+            vis: f.vis,
+            name: Some(name),
+            anonymous: false,
+            key_type,
+            value_type,
+            initializer,
+            explicit_initializer,
+            attr_errors: entry_attr_errors,
+            cfgs: cfg_attributes(&f.attrs),
+        });
+    }
+
+    parsed_attrs.dynamic = dynamic_field_name.is_some();
+    let additional_key_constraints = key_constraints(&parsed_attrs);
+    let crate_path = crate_path(&parsed_attrs);
+    let dynamic_cell_name = cell_name(&parsed_attrs, &input.ident);
+    let key_enum_name = key_enum_name(&parsed_attrs, &input.ident);
+    let diff_type_name = diff_name(&parsed_attrs, &input.ident);
+
+    Ok(Map {
+        forwarded_attrs: vec![],
+        parsed_attrs,
+        vis: input.vis,
+        dynamic_cell_name,
+        key_enum_name,
+        diff_type_name,
+        name: input.ident,
+        entries,
+        dynamic_field_name: dynamic_field_name.unwrap_or_else(|| quote::format_ident!("not_set")),
+        additional_key_constraints,
+        attr_errors,
+        crate_path,
+    })
+}
+
+/// The name for an entry declared as `_`, built from the last segment of the key's path, e.g. `sound_plugin` for
+/// `plugins::SoundPlugin`.
+///
+/// Returns `None` for types which aren't paths, such as references and tuples, and if the name would be a keyword.
+fn name_from_type(ty: &syn::Type) -> Option<String> {
+    let path = match ty {
+        syn::Type::Path(p) if p.qself.is_none() => &p.path,
+        _ => return None,
+    };
+    let name = to_snake_case(&path.segments.last()?.ident.to_string());
+    syn::parse_str::<syn::Ident>(&name).ok()?;
+    Some(name)
+}
+
 /// Make sure every entry in the map has a name.
 ///
+/// Entries declared as `_` are named after their key type, so that adding an entry doesn't rename the others.  If that
+/// isn't possible, or the name is already taken, they fall back to `typemap_0`, `typemap_1`, and so on.  These names
+/// are hygienic as locals, so that they don't shadow anything the initializers of later entries use.
+///
 /// Also generate the name of the field for dynamic entries.
 fn ensure_names(map: &mut Map) {
-    let mut used_names = HashSet::new();
+    let mut used_names = map
+        .entries
+        .iter()
+        .filter(|e| e.name.is_some())
+        .map(MapEntry::field_name)
+        .collect::<HashSet<_>>();
     let mut ind = 0;
 
-    for m in map.entries.iter_mut() {
-        if m.name.is_none() {
-            loop {
-                let n = format!("typemap_{}", ind);
-                if used_names.contains(&n) {
-                    ind += 1;
-                    continue;
-                }
-                m.name = Some(syn::Ident::new(&n, proc_macro2::Span::call_site()));
+    for m in map.entries.iter_mut().filter(|e| e.name.is_none()) {
+        if let Some(n) = name_from_type(&m.key_type).filter(|n| !used_names.contains(n)) {
+            m.name = Some(syn::Ident::new(&n, proc_macro2::Span::mixed_site()));
+            used_names.insert(n);
+            continue;
+        }
 
-                used_names.insert(n);
-                break;
+        loop {
+            let n = format!("typemap_{}", ind);
+            if used_names.contains(&n) {
+                ind += 1;
+                continue;
             }
-        } else {
-            used_names.insert(m.name.as_ref().unwrap().to_string());
+            m.name = Some(syn::Ident::new(&n, proc_macro2::Span::mixed_site()));
+
+            used_names.insert(n);
+            break;
         }
     }
 
@@ -169,94 +744,860 @@ fn ensure_names(map: &mut Map) {
     }
 }
 
-/// Builds the cell type of the map, which is used in dynamic contexts to hold map entries.
-///
-/// If the map isn't dynamic, returns an empty token stream.
-fn build_cell_type(map: &Map) -> TokenStream2 {
-    if !map.parsed_attrs.dynamic {
-        return quote!();
+impl MapEntry {
+    /// The name of the accessor methods for this entry, if it has them.
+    ///
+    /// Entries declared as `_` get accessors named after their field, since there's no other way to name them.
+    fn accessor(&self) -> Option<syn::Ident> {
+        match (&self.parsed_attrs.accessor, self.anonymous) {
+            (Some(a), _) => Some(a.clone()),
+            (None, true) => self
+                .name
+                .as_ref()
+                .map(|n| syn::Ident::new(&n.to_string(), proc_macro2::Span::call_site())),
+            (None, false) => None,
+        }
     }
 
-    // This type consists of a name, and then a set of function pointers which downcast to all iterable traits named as
-    // the method that they go with.  The function pointers are of the form `username` and `username_mut`, and are used
-    // to implement per-trait iteration.
-    //
-    // Each function pointer takes a `&dyn Any` and infallibly casts to the type of the object in the cell, then to the
-    // trait object that type would generate. To avoid having to put named functions in a module, we just use the fact
-    // that closures coerce to function pointers if they don't capture.
+    /// The name of this entry's field, without the `r#` of a raw identifier, for use in strings and derived names.
+    fn field_name(&self) -> String {
+        syn::ext::IdentExt::unraw(self.name.as_ref().unwrap()).to_string()
+    }
 
-    let name = &map.dynamic_cell_name;
+    /// The name of this entry's variant in the key enum, which is its field name in camel case.
+    ///
+    /// This may not be a valid identifier, e.g. for a field named `self_`, which `check_variant_names` reports.
+    fn variant_name(&self) -> String {
+        to_camel_case(&self.field_name())
+    }
 
-    let mut field_decls = vec![];
-    let mut initializers = vec![];
+    /// The name of this entry's variant in the key enum, as an identifier.
+    ///
+    /// This is always usable from outside the macro, even when the field name is hygienic.
+    fn variant(&self) -> syn::Ident {
+        syn::Ident::new(&self.variant_name(), proc_macro2::Span::call_site())
+    }
 
-    for (path, field_name) in map.parsed_attrs.iterable_traits.iter() {
-        let name_mut = quote::format_ident!("{}_mut", field_name);
+    /// The key `apply_config` sets this entry with, if it can.
+    ///
+    /// Named entries can be unless they opt out.  Entries declared as `_` have to opt in, since their names come from
+    /// their types and would change with them.
+    fn config_key(&self) -> Option<String> {
+        match (&self.parsed_attrs.config, self.anonymous) {
+            (Some(EntryConfig::Rename(key)), _) => Some(key.clone()),
+            (Some(EntryConfig::Skip), _) | (None, true) => None,
+            (Some(EntryConfig::Include), _) | (None, false) => Some(self.field_name()),
+        }
+    }
 
-        field_decls.push(quote!(#field_name: fn(&dyn core::any::Any) -> &dyn #path));
-        field_decls.push(quote!(#name_mut: fn(&mut dyn core::any::Any) -> &mut dyn #path));
+    /// Whether `save` and `load` include this entry.
+    fn is_persisted(&self, map: &Map) -> bool {
+        map.parsed_attrs.persist.is_some() && self.parsed_attrs.persist != Some(false)
+    }
 
-        for (fieldname, ref_or_mut, maybe_mut) in [
-            (field_name, "ref", quote!()),
-            (&name_mut, "mut", quote!(mut)),
-        ] {
-            let dcast = quote::format_ident!("downcast_{}", ref_or_mut);
-            initializers.push(quote!(
-                #fieldname: |x| match x.#dcast::<K>() {
-                    Some(x) => (&#maybe_mut *x) as &#maybe_mut dyn #path,
-                    None => unsafe { core::hint::unreachable_unchecked() }
-                }
-            ));
+    /// An expression for the key this entry is saved under.
+    fn stable_key(&self, map: &Map) -> TokenStream2 {
+        let krate = &map.crate_path;
+        let key_type = &self.key_type;
+        match &self.parsed_attrs.key {
+            Some(k) => quote!(#k),
+            None => quote!(<#key_type as #krate::StableKey>::KEY),
         }
     }
 
-    let constraints = &map.additional_key_constraints;
+    fn is_lazy(&self) -> bool {
+        self.parsed_attrs.lazy
+    }
 
-    quote!(
-        /// Exposed only so that it is possible to name iterator types.
-        pub struct #name {
-        value: std::boxed::Box<dyn std::any::Any>,
-        #(#field_decls),*
+    fn is_boxed(&self) -> bool {
+        self.parsed_attrs.boxed
     }
 
-    impl #name {
-        fn new<K: core::any::Any + #(#constraints)+*>(value: K) -> Self {
-            Self {
-                value: Box::new(value),
-                #(#initializers),*
-            }
+    /// The type of the value stored for this entry, which is the key unless the entry was declared as `Key => Value`.
+    fn value_type(&self) -> &syn::Type {
+        self.value_type.as_ref().unwrap_or(&self.key_type)
+    }
+
+    /// The type which holds this entry's value: the value itself, or a box around it.
+    fn value_storage_type(&self) -> TokenStream2 {
+        let value_type = self.value_type();
+        if self.is_boxed() {
+            quote!(::std::boxed::Box<#value_type>)
+        } else {
+            quote!(#value_type)
         }
-    })
-}
+    }
 
-/// Define the struct itself.
-fn build_struct(map: &Map) -> TokenStream2 {
-    let mut fields = vec![];
+    /// The type of the struct field which holds this entry.
+    fn storage_type(&self, map: &Map) -> TokenStream2 {
+        let storage = self.value_storage_type();
+        if self.is_lazy() {
+            let cell = lazy_cell_type(map);
+            quote!(#cell<#storage>)
+        } else {
+            storage
+        }
+    }
 
-    for e in map.entries.iter() {
-        let name = e.name.as_ref().unwrap();
-        let MapEntry {
-            ref vis,
-            ref attrs,
-            ref key_type,
-            ..
-        } = e;
-        fields.push(quote!(#(#attrs)* #vis #name : #key_type));
+    /// Wrap an expression producing the entry's value so that it is of the `value_storage_type`.
+    fn wrap_value(&self, value: TokenStream2) -> TokenStream2 {
+        if self.is_boxed() {
+            quote!(::std::boxed::Box::new(#value))
+        } else {
+            value
+        }
     }
 
-    if map.parsed_attrs.dynamic {
-        let dn = &map.dynamic_field_name;
-        let cn = &map.dynamic_cell_name;
-        fields.push(quote!(#dn: std::collections::HashMap<std::any::TypeId, #cn>));
+    /// Name of the private function which runs the initializer of a lazy entry.
+    fn lazy_init_fn(&self) -> syn::Ident {
+        quote::format_ident!("init_lazy_{}", self.name.as_ref().unwrap())
     }
 
-    let forwarded_attrs = &map.forwarded_attrs;
-    let name = &map.name;
-    let vis = &map.vis;
-    quote!(#(#forwarded_attrs)* #vis struct #name { #(#fields),* })
-}
+    /// An expression which borrows this entry from `this`, which must be an expression evaluating to the map.
+    ///
+    /// Lazy entries are initialized by this if needed.
+    fn borrow(&self, map: &Map, this: TokenStream2, is_mut: bool) -> TokenStream2 {
+        let field = self.name.as_ref().unwrap();
+        let maybe_mut = if is_mut { quote!(mut) } else { quote!() };
 
-/// Work out the type needed to iterate by a specific trait for the given map, with the given mutability.
+        if !self.is_lazy() {
+            if self.is_boxed() {
+                return quote!(&#maybe_mut *#this.#field);
+            }
+            return quote!(&#maybe_mut #this.#field);
+        }
+
+        let map_name = &map.name;
+        let init = self.lazy_init_fn();
+        let stored = if !is_mut {
+            quote!(#this.#field.get_or_init(#map_name::#init))
+        } else {
+            // The cells can't initialize through a mutable reference on stable, so do it in two steps.
+            let unwrapper = fast_unwrap(quote!(#this.#field.get_mut()));
+            quote!({
+                #this.#field.get_or_init(#map_name::#init);
+                #unwrapper
+            })
+        };
+
+        if self.is_boxed() {
+            quote!(&#maybe_mut **#stored)
+        } else {
+            stored
+        }
+    }
+
+    /// An expression which puts `value` into this entry of `this`, and evaluates to the old value if there was one.
+    ///
+    /// Unlike writing through `borrow`, this doesn't run the initializer of a lazy entry only to throw its result away.
+    fn store(&self, this: TokenStream2, value: TokenStream2) -> TokenStream2 {
+        let field = self.name.as_ref().unwrap();
+
+        if !self.is_lazy() {
+            let dest = if self.is_boxed() {
+                quote!(&mut *#this.#field)
+            } else {
+                quote!(&mut #this.#field)
+            };
+            return quote!(::core::option::Option::Some(::core::mem::replace(#dest, #value)));
+        }
+
+        let wrapped = self.wrap_value(quote!(value));
+        let dest = if self.is_boxed() {
+            quote!(&mut **x)
+        } else {
+            quote!(x)
+        };
+        quote!({
+            let value = #value;
+            match #this.#field.get_mut() {
+                ::core::option::Option::Some(x) => ::core::option::Option::Some(::core::mem::replace(#dest, value)),
+                ::core::option::Option::None => {
+                    let _ = #this.#field.set(#wrapped);
+                    ::core::option::Option::None
+                }
+            }
+        })
+    }
+
+    /// Like `borrow`, but returns an `Option` which is `None` for lazy entries that haven't been initialized yet.
+    fn borrow_if_initialized(&self, map: &Map, this: TokenStream2, is_mut: bool) -> TokenStream2 {
+        if !self.is_lazy() {
+            let borrowed = self.borrow(map, this, is_mut);
+            return quote!(::core::option::Option::Some(#borrowed));
+        }
+
+        let field = self.name.as_ref().unwrap();
+        let getter = if is_mut { quote!(get_mut) } else { quote!(get) };
+        if self.is_boxed() {
+            let maybe_mut = if is_mut { quote!(mut) } else { quote!() };
+            quote!(#this.#field.#getter().map(|x| &#maybe_mut **x))
+        } else {
+            quote!(#this.#field.#getter())
+        }
+    }
+}
+
+/// The cell lazy entries are stored in.
+///
+/// This is a `OnceCell` unless the map has `const_new`, whose point is putting the map in a static, which needs it to be
+/// `Sync`.  Those use a `OnceLock` instead, which has the same API.
+fn lazy_cell_type(map: &Map) -> TokenStream2 {
+    if map.parsed_attrs.const_new {
+        quote!(::std::sync::OnceLock)
+    } else {
+        quote!(::core::cell::OnceCell)
+    }
+}
+
+/// Whether trait iteration over this map can skip some entries, which changes the type of the iterators.
+fn iteration_skips_entries(map: &Map) -> bool {
+    map.parsed_attrs.lazy_iteration == LazyIteration::Skip
+        && map.entries.iter().any(|e| e.is_lazy())
+}
+
+/// Whether the array of fixed entries used by trait iteration has to be of options, which get flattened.
+///
+/// This is the case if entries can be skipped, and also if any are behind `cfg`: attributes aren't allowed on array
+/// elements, so those entries are `None` when configured out.
+fn iteration_uses_options(map: &Map) -> bool {
+    iteration_skips_entries(map) || map.entries.iter().any(|e| !e.cfgs.is_empty())
+}
+
+/// The number of fixed entries, counting only those which are configured in.
+fn fixed_len(map: &Map) -> TokenStream2 {
+    if map.entries.iter().all(|e| e.cfgs.is_empty()) {
+        let len = map.entries.len();
+        return quote!(#len);
+    }
+
+    let increments = map.entries.iter().map(|e| {
+        let cfgs = &e.cfgs;
+        quote!(#(#cfgs)* { n += 1; })
+    });
+    quote!({
+        let mut n = 0usize;
+        #(#increments)*
+        n
+    })
+}
+
+/// Find any uses of `names` as a plain identifier in an initializer, and return the first one.
+///
+/// This works on tokens, since initializers can contain macros and we'd otherwise have to parse those.  To avoid false
+/// positives, only paths of one segment which aren't called count, so identifiers which are a field access, a path
+/// segment, a function or macro name, or a struct literal field aren't considered to be references.
+fn find_reference<'a>(
+    tokens: TokenStream2,
+    names: &[&'a syn::Ident],
+) -> Option<(&'a syn::Ident, proc_macro2::Span)> {
+    use proc_macro2::TokenTree;
+
+    let tokens = tokens.into_iter().collect::<Vec<_>>();
+    let is_punct =
+        |i: Option<&TokenTree>, c: char| matches!(i, Some(TokenTree::Punct(p)) if p.as_char() == c);
+
+    for (i, tok) in tokens.iter().enumerate() {
+        match tok {
+            TokenTree::Group(g) => {
+                if let Some(found) = find_reference(g.stream(), names) {
+                    return Some(found);
+                }
+            }
+            TokenTree::Ident(ident) => {
+                let prev = |n: usize| i.checked_sub(n).and_then(|x| tokens.get(x));
+                let next = tokens.get(i + 1);
+                let path_segment = is_punct(prev(1), ':') && is_punct(prev(2), ':');
+                if is_punct(prev(1), '.')
+                    || path_segment
+                    || is_punct(next, '!')
+                    || is_punct(next, ':')
+                    || matches!(next, Some(TokenTree::Group(g)) if g.delimiter() == proc_macro2::Delimiter::Parenthesis)
+                {
+                    continue;
+                }
+
+                if let Some(n) = names.iter().find(|n| *n == &ident) {
+                    return Some((n, ident.span()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Entries are built in declaration order, so an initializer may only refer to entries declared before it.  An entry's own
+/// name isn't in scope in its initializer, so it's free to use for something else, e.g. `config: Config = config()`.
+///
+/// Lazy entries aren't built in `new`, so they can't refer to other entries and other entries can't refer to them.
+fn check_initializer_references(map: &Map) -> syn::Result<()> {
+    for (i, e) in map.entries.iter().enumerate() {
+        // Entries declared as `_` can't be referred to, so their names are free for initializers to use.
+        let lazy = map.entries[..i].iter().filter(|x| x.is_lazy());
+        let forbidden = if e.is_lazy() {
+            map.entries[..i]
+                .iter()
+                .chain(&map.entries[i + 1..])
+                .filter(|x| !x.anonymous)
+                .filter_map(|x| x.name.as_ref())
+                .collect::<Vec<_>>()
+        } else {
+            map.entries[i + 1..]
+                .iter()
+                .chain(lazy)
+                .filter(|x| !x.anonymous)
+                .filter_map(|x| x.name.as_ref())
+                .collect::<Vec<_>>()
+        };
+        let initializer = &e.initializer;
+
+        if let Some((name, span)) = find_reference(quote!(#initializer), &forbidden) {
+            let referenced = map
+                .entries
+                .iter()
+                .find(|x| x.name.as_ref() == Some(name))
+                .unwrap();
+            let msg = if e.is_lazy() {
+                format!(
+                    "lazy entries are initialized on first access, so their initializer can't refer to `{}`",
+                    name
+                )
+            } else if referenced.is_lazy() {
+                format!(
+                    "`{}` is lazy and isn't built when the map is, so other initializers can't refer to it",
+                    name
+                )
+            } else {
+                format!(
+                    "`{}` is declared after this entry; entries are initialized in declaration order, so initializers may only refer to earlier entries",
+                    name
+                )
+            };
+            return Err(syn::Error::new(span, msg));
+        }
+    }
+
+    Ok(())
+}
+
+/// Report every error from parsing our attributes, on the map and on all of its entries.
+fn check_attributes(map: &Map) -> syn::Result<()> {
+    combine_errors(
+        map.attr_errors
+            .iter()
+            .chain(map.entries.iter().filter_map(|e| e.attr_errors.as_ref()))
+            .cloned(),
+    )
+}
+
+/// Methods every map has, which names chosen by the user mustn't collide with.
+const BUILTIN_METHODS: &[&str] = &[
+    "new",
+    "try_new",
+    "get",
+    "get_mut",
+    "contains",
+    "get_infallible",
+    "get_infallible_mut",
+    "get_keyed",
+    "get_keyed_mut",
+    "insert",
+    "insert_keyed",
+    "insert_infallible",
+    "insert_value",
+    "insert_boxed",
+    "get_const_ptr",
+    "get_mut_ptr",
+    "get_by_type_id",
+    "get_by_type_id_mut",
+    "len",
+    "is_empty",
+    "index_of",
+    "get_by_index",
+    "get_by_index_mut",
+    "get_by_name",
+    "get_by_name_mut",
+    "names",
+    "insert_by_name",
+    "visit",
+    "visit_mut",
+    "zip_visit",
+    "zip_visit_mut",
+];
+
+/// Check that the methods named after traits and fields don't collide with each other or with the builtin ones.
+///
+/// Fields and methods are in different namespaces, so fields only matter through the methods named after them.
+fn check_method_names(map: &Map) -> syn::Result<()> {
+    let mut generated: Vec<(String, proc_macro2::Span, String)> = vec![];
+
+    // Sorted, so that which of two colliding names gets the error doesn't depend on hashing.
+    let mut traits = map.parsed_attrs.iterable_traits.iter().collect::<Vec<_>>();
+    traits.sort_by_key(|(path, _)| quote!(#path).to_string());
+    for (path, method) in traits {
+        let span = syn::spanned::Spanned::span(path);
+        let trait_name = quote!(#path).to_string().replace(' ', "");
+        let get_as = get_as_method_name(path).to_string();
+        for name in [
+            method.to_string(),
+            format!("{}_mut", method),
+            format!("{}_mut", get_as),
+            get_as,
+        ] {
+            generated.push((name, span, format!("iterating by `{}`", trait_name)));
+        }
+    }
+
+    if map.parsed_attrs.config {
+        let purpose = "the `config` option".to_string();
+        generated.push(("apply_config".to_string(), map.name.span(), purpose));
+    }
+
+    if map.parsed_attrs.diff {
+        for name in ["diff", "apply"] {
+            let purpose = "the `diff` option".to_string();
+            generated.push((name.to_string(), map.name.span(), purpose));
+        }
+    }
+
+    if map.parsed_attrs.persist.is_some() {
+        for name in ["save", "load"] {
+            let purpose = "the `persist` option".to_string();
+            generated.push((name.to_string(), map.name.span(), purpose));
+        }
+    }
+
+    for e in map.entries.iter().filter(|e| e.is_lazy()) {
+        let span = syn::spanned::Spanned::span(&e.key_type);
+        let name = e.lazy_init_fn().to_string();
+        generated.push((name, span, "initializing a lazy entry".to_string()));
+    }
+
+    for e in map.entries.iter() {
+        if let Some(accessor) = e.accessor() {
+            let span = syn::spanned::Spanned::span(&e.key_type);
+            let key = &e.key_type;
+            let purpose = format!("accessing `{}`", quote!(#key));
+            generated.push((format!("{}_mut", accessor), span, purpose.clone()));
+            generated.push((accessor.to_string(), span, purpose));
+        }
+    }
+
+    let mut errors = vec![];
+    let mut seen: std::collections::HashMap<&str, &str> = Default::default();
+    for (name, span, purpose) in generated.iter() {
+        if BUILTIN_METHODS.contains(&name.as_str()) {
+            errors.push(syn::Error::new(
+                *span,
+                format!(
+                    "the method `{}` for {} collides with a method every map has{}",
+                    name,
+                    purpose,
+                    accessor_hint(purpose)
+                ),
+            ));
+        } else if let Some(other) = seen.get(name.as_str()) {
+            errors.push(syn::Error::new(
+                *span,
+                format!(
+                    "the method `{}` for {} is already generated for {}{}",
+                    name,
+                    purpose,
+                    other,
+                    accessor_hint(purpose)
+                ),
+            ));
+        } else {
+            seen.insert(name, purpose);
+        }
+    }
+
+    combine_errors(errors)
+}
+
+/// Check that the types generated next to the map don't share a name with each other or with the map.
+///
+/// Each has an option to rename it, which the error points out.  Other types in the module are left to the compiler.
+fn check_type_names(map: &Map) -> syn::Result<()> {
+    let opts = &map.parsed_attrs;
+    let mut names = vec![
+        (&map.name, "the map", None),
+        (&map.key_enum_name, "the key enum", Some("key_enum")),
+    ];
+    if opts.dynamic {
+        names.push((
+            &map.dynamic_cell_name,
+            "the cell type of the dynamic section",
+            Some("cell_name"),
+        ));
+    }
+    if opts.diff {
+        names.push((&map.diff_type_name, "the diff", Some("diff_name")));
+    }
+
+    let mut errors = vec![];
+    for (i, (name, purpose, option)) in names.iter().enumerate() {
+        if let Some((_, other, _)) = names[..i].iter().find(|(n, _, _)| n == name) {
+            errors.push(syn::Error::new(
+                map.name.span(),
+                format!(
+                    "`{}` would be the name of both {} and {}; use `#[fixed_typemap({} = \"...\")]` to choose another name",
+                    name,
+                    other,
+                    purpose,
+                    option.unwrap()
+                ),
+            ));
+        }
+    }
+
+    combine_errors(errors)
+}
+
+/// For collisions involving accessors, point out how to rename them.
+fn accessor_hint(purpose: &str) -> &'static str {
+    if purpose.starts_with("accessing") {
+        "; use `#[fixed_typemap(accessor = \"...\")]` to choose another name"
+    } else {
+        ""
+    }
+}
+
+/// Check that no key type appears twice, pointing the error at both entries.
+///
+/// This only catches keys that are spelled the same way.  Aliases are caught by the compiler, through the check from
+/// `build_key_uniqueness_check`.
+fn check_duplicate_keys(map: &Map) -> syn::Result<()> {
+    let mut seen: std::collections::HashMap<String, &MapEntry> = Default::default();
+
+    // Entries behind `cfg` may well be alternatives for each other, so those are left to the compiler.
+    for e in map.entries.iter().filter(|e| e.cfgs.is_empty()) {
+        let key = &e.key_type;
+        let spelled = quote!(#key).to_string();
+        if let Some(first) = seen.get(&spelled) {
+            let mut err = syn::Error::new_spanned(
+                key,
+                format!(
+                    "`{}` is already a key of this map; each type can only key one entry",
+                    spelled
+                ),
+            );
+            err.combine(syn::Error::new_spanned(
+                &first.key_type,
+                format!("`{}` is first used as a key here", spelled),
+            ));
+            return Err(err);
+        }
+        seen.insert(spelled, e);
+    }
+
+    Ok(())
+}
+
+/// Check that every entry gets a valid variant in the key enum, and that no two get the same one, which happens if their
+/// names differ only in underscores.
+fn check_variant_names(map: &Map) -> syn::Result<()> {
+    // Keywords such as `Self`, and names which are empty or start with a digit once the underscores are gone.
+    for e in map.entries.iter() {
+        let variant = e.variant_name();
+        if syn::parse_str::<syn::Ident>(&variant).is_err() {
+            return Err(syn::Error::new_spanned(
+                e.name.as_ref().unwrap(),
+                format!(
+                    "the variant of `{}` for this entry would be `{}`, which isn't a valid identifier; rename the entry",
+                    map.key_enum_name, variant
+                ),
+            ));
+        }
+    }
+
+    let mut seen: std::collections::HashMap<String, &MapEntry> = Default::default();
+
+    for e in map.entries.iter().filter(|e| e.cfgs.is_empty()) {
+        let variant = e.variant_name();
+        if let Some(first) = seen.get(&variant) {
+            return Err(syn::Error::new_spanned(
+                &e.key_type,
+                format!(
+                    "this entry and the one named `{}` would both be `{}::{}`; rename one of them",
+                    first.name.as_ref().unwrap(),
+                    map.key_enum_name,
+                    variant
+                ),
+            ));
+        }
+        seen.insert(variant, e);
+    }
+
+    Ok(())
+}
+
+/// Find the first `?` in some tokens, which in an initializer is the try operator.
+fn find_question_mark(tokens: TokenStream2) -> Option<proc_macro2::Span> {
+    use proc_macro2::TokenTree;
+
+    tokens.into_iter().find_map(|tok| match tok {
+        TokenTree::Group(g) => find_question_mark(g.stream()),
+        TokenTree::Punct(p) if p.as_char() == '?' => Some(p.span()),
+        _ => None,
+    })
+}
+
+/// Check that the options given to the map make sense together.
+fn check_options(map: &Map) -> syn::Result<()> {
+    let opts = &map.parsed_attrs;
+
+    // Lazy initializers run on first access, from getters which have nowhere to put an error.
+    if opts.fallible.is_some() {
+        for e in map.entries.iter().filter(|e| e.is_lazy()) {
+            let initializer = &e.initializer;
+            if let Some(span) = find_question_mark(quote!(#initializer)) {
+                return Err(syn::Error::new(
+                    span,
+                    "lazy entries are built on first access, where there's no way to report an error, so their initializers can't use `?`",
+                ));
+            }
+        }
+    }
+
+    if opts.const_new {
+        if opts.dynamic {
+            return Err(syn::Error::new(
+                map.name.span(),
+                "`const_new` can't be used with `dynamic`, since the dynamic section can't be built in a const context",
+            ));
+        }
+
+        if opts.fallible.is_some() {
+            return Err(syn::Error::new(
+                map.name.span(),
+                "`const_new` can't be used with `fallible`, since `?` isn't allowed in a const context",
+            ));
+        }
+
+        if let Some(e) = map.entries.iter().find(|e| e.is_boxed() && !e.is_lazy()) {
+            return Err(syn::Error::new_spanned(
+                &e.key_type,
+                "maps with `const_new` can't have boxed entries unless they're also lazy, since allocation isn't const",
+            ));
+        }
+
+        // Lazy entries are built on first access, so only the rest need to be const.
+        if let Some(e) = map
+            .entries
+            .iter()
+            .find(|e| !e.explicit_initializer && !e.is_lazy())
+        {
+            return Err(syn::Error::new_spanned(
+                &e.key_type,
+                "maps with `const_new` need an initializer for every entry, since `Default::default()` isn't const",
+            ));
+        }
+    }
+
+    if !opts.diff && opts.diff_name.is_some() {
+        return Err(syn::Error::new(
+            map.name.span(),
+            "`diff_name` names the type returned by `diff`, so it needs `#[fixed_typemap(diff)]`",
+        ));
+    }
+
+    if opts.persist.is_none() {
+        let persist_attrs = |e: &&MapEntry| {
+            let p = &e.parsed_attrs;
+            p.key.is_some() || p.migrate.is_some() || p.persist.is_some()
+        };
+        if let Some(e) = map.entries.iter().find(persist_attrs) {
+            return Err(syn::Error::new_spanned(
+                &e.key_type,
+                "`key`, `migrate` and `persist` on an entry need `#[fixed_typemap(persist(version = ...))]` on the map",
+            ));
+        }
+    } else {
+        // Keys from `StableKey` are only known to the compiler, so only the explicit ones can be checked here.
+        let mut seen: std::collections::HashMap<&str, &MapEntry> = Default::default();
+        for e in map
+            .entries
+            .iter()
+            .filter(|e| e.cfgs.is_empty() && e.is_persisted(map))
+        {
+            let key = match &e.parsed_attrs.key {
+                Some(k) => k.as_str(),
+                None => continue,
+            };
+            if let Some(first) = seen.get(key) {
+                return Err(syn::Error::new_spanned(
+                    &e.key_type,
+                    format!(
+                        "the stable key `{}` is already used by the entry named `{}`",
+                        key,
+                        first.name.as_ref().unwrap()
+                    ),
+                ));
+            }
+            seen.insert(key, e);
+        }
+    }
+
+    if !opts.config {
+        if let Some(e) = map.entries.iter().find(|e| e.parsed_attrs.config.is_some()) {
+            return Err(syn::Error::new_spanned(
+                &e.key_type,
+                "`config` on an entry needs `#[fixed_typemap(config)]` on the map",
+            ));
+        }
+    } else {
+        // As with keys, entries behind `cfg` may be alternatives for each other.
+        let mut seen: std::collections::HashMap<String, &MapEntry> = Default::default();
+        for e in map.entries.iter().filter(|e| e.cfgs.is_empty()) {
+            let key = match e.config_key() {
+                Some(k) => k,
+                None => continue,
+            };
+            if let Some(first) = seen.get(&key) {
+                return Err(syn::Error::new_spanned(
+                    &e.key_type,
+                    format!(
+                        "the config key `{}` is already used by the entry named `{}`",
+                        key,
+                        first.name.as_ref().unwrap()
+                    ),
+                ));
+            }
+            seen.insert(key, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the cell type of the map, which is used in dynamic contexts to hold map entries.
+///
+/// If the map isn't dynamic, returns an empty token stream.
+fn build_cell_type(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    if !map.parsed_attrs.dynamic {
+        return quote!();
+    }
+
+    // This type consists of a name, and then a set of function pointers which downcast to all iterable traits named as
+    // the method that they go with.  The function pointers are of the form `username` and `username_mut`, and are used
+    // to implement per-trait iteration.
+    //
+    // Each function pointer takes a `&dyn Any` and infallibly casts to the type of the object in the cell, then to the
+    // trait object that type would generate. To avoid having to put named functions in a module, we just use the fact
+    // that closures coerce to function pointers if they don't capture.
+
+    let name = &map.dynamic_cell_name;
+
+    let mut field_decls = vec![];
+    let mut initializers = vec![];
+
+    for (path, field_name) in map.parsed_attrs.iterable_traits.iter() {
+        let name_mut = quote::format_ident!("{}_mut", field_name);
+
+        field_decls.push(quote!(#field_name: fn(&dyn ::core::any::Any) -> &dyn #path));
+        field_decls.push(quote!(#name_mut: fn(&mut dyn ::core::any::Any) -> &mut dyn #path));
+
+        for (fieldname, ref_or_mut, maybe_mut) in [
+            (field_name, "ref", quote!()),
+            (&name_mut, "mut", quote!(mut)),
+        ] {
+            let dcast = quote::format_ident!("downcast_{}", ref_or_mut);
+            initializers.push(quote!(
+                #fieldname: |x| match x.#dcast::<K>() {
+                    ::core::option::Option::Some(x) => (&#maybe_mut *x) as &#maybe_mut dyn #path,
+                    ::core::option::Option::None => unsafe { ::core::hint::unreachable_unchecked() }
+                }
+            ));
+        }
+    }
+
+    // Diffing compares and clones values, which likewise needs to know their types.
+    let mut clone_cell = quote!();
+    if map.parsed_attrs.diff {
+        field_decls.push(quote!(diff_eq: fn(&dyn ::core::any::Any, &dyn ::core::any::Any) -> bool));
+        field_decls.push(quote!(diff_clone: fn(&dyn ::core::any::Any) -> ::std::boxed::Box<dyn ::core::any::Any>));
+        initializers.push(quote!(
+            diff_eq: |a, b| match (a.downcast_ref::<K>(), b.downcast_ref::<K>()) {
+                (::core::option::Option::Some(a), ::core::option::Option::Some(b)) => ::core::cmp::PartialEq::eq(a, b),
+                _ => false,
+            }
+        ));
+        initializers.push(quote!(
+            diff_clone: |x| match x.downcast_ref::<K>() {
+                ::core::option::Option::Some(x) => ::std::boxed::Box::new(::core::clone::Clone::clone(x)),
+                ::core::option::Option::None => unsafe { ::core::hint::unreachable_unchecked() }
+            }
+        ));
+        clone_cell = quote!(
+            fn clone_cell(&self) -> Self {
+                Self {
+                    value: (self.diff_clone)(&*self.value),
+                    ..*self
+                }
+            }
+        );
+    }
+
+    let constraints = &map.additional_key_constraints;
+    let map_name = &map.name;
+
+    quote!(
+        impl #krate::HasDynamicSection for #map_name {
+            type Cell = #name;
+        }
+
+        /// Exposed only so that it is possible to name iterator types.
+        #vis struct #name {
+        value: ::std::boxed::Box<dyn ::std::any::Any>,
+        /// The name of the key, for `get_by_name`.  Values inserted through `insert_boxed` don't have one.
+        type_name: ::core::option::Option<&'static str>,
+        #(#field_decls),*
+    }
+
+    impl #name {
+        fn new<K: ::core::any::Any + #(#constraints)+*>(type_name: &'static str, value: K) -> Self {
+            Self {
+                value: ::std::boxed::Box::new(value),
+                type_name: ::core::option::Option::Some(type_name),
+                #(#initializers),*
+            }
+        }
+
+        #clone_cell
+    })
+}
+
+/// Define the struct itself.
+fn build_struct(map: &Map) -> TokenStream2 {
+    let mut fields = vec![];
+
+    for e in map.entries.iter() {
+        let name = e.name.as_ref().unwrap();
+        let MapEntry {
+            ref vis, ref attrs, ..
+        } = e;
+        let storage_type = e.storage_type(map);
+        fields.push(quote!(#(#attrs)* #vis #name : #storage_type));
+    }
+
+    if map.parsed_attrs.dynamic {
+        let dn = &map.dynamic_field_name;
+        let cn = &map.dynamic_cell_name;
+        fields.push(quote!(#dn: ::std::collections::HashMap<::std::any::TypeId, #cn>));
+    }
+
+    let forwarded_attrs = &map.forwarded_attrs;
+    let name = &map.name;
+    let vis = &map.vis;
+    quote!(#(#forwarded_attrs)* #vis struct #name { #(#fields),* })
+}
+
+/// Work out the type needed to iterate by a specific trait for the given map, with the given mutability.
 fn build_iter_type(
     map: &Map,
     trait_name: &syn::Path,
@@ -266,338 +1607,2021 @@ fn build_iter_type(
     // The first step is an IntoIter for the array portion.
     let maybe_mut = if is_mut { quote!(mut) } else { quote!() };
     let arr_len = map.entries.len();
-    let static_part =
-        quote!(core::array::IntoIter<&#lt #maybe_mut (dyn #trait_name + #lt), #arr_len>);
+    let mut static_part =
+        quote!(::core::array::IntoIter<&#lt #maybe_mut (dyn #trait_name + #lt), #arr_len>);
+    if iteration_uses_options(map) {
+        // Entries which might be skipped are options in the array, which we then flatten.
+        static_part = quote!(::core::iter::Flatten<::core::array::IntoIter<::core::option::Option<&#lt #maybe_mut (dyn #trait_name + #lt)>, #arr_len>>);
+    }
+
+    let dynamic_part = if map.parsed_attrs.dynamic {
+        // If the array is dynamic, we need the iterator from the hashmap, which is a map over the values to convert
+        // from the cell type to the dynamic reference.
+        let celltype = &map.dynamic_cell_name;
+        let map_iter_type = if is_mut {
+            quote!(ValuesMut)
+        } else {
+            quote!(Values)
+        };
+
+        quote!(::core::iter::Map<::std::collections::hash_map::#map_iter_type<#lt, ::core::any::TypeId, #celltype>, for<'r> fn(&'r #maybe_mut #celltype) -> &'r #maybe_mut (dyn #trait_name + 'r)>)
+    } else {
+        // Otherwise, it's the empty iterator.
+        quote!(::core::iter::Empty<&#lt #maybe_mut dyn #trait_name>)
+    };
+
+    // The result is the chain of these types.
+    quote!(::core::iter::Chain<#static_part, #dynamic_part>)
+}
+
+/// Implement all the traits we want to implement.
+fn build_trait_impls(map: &Map) -> TokenStream2 {
+    let krate = &map.crate_path;
+    let name = &map.name;
+
+    let mut impls = vec![];
+
+    for e in map.entries.iter() {
+        let key_type = &e.key_type;
+        let value_type = e.value_type();
+        let borrow = e.borrow(map, quote!(map), false);
+        let borrow_mut = e.borrow(map, quote!(map), true);
+        let store = e.store(quote!(map), quote!(value));
+        let cfgs = &e.cfgs;
+        impls.push(
+            quote!(#(#cfgs)* unsafe impl #krate::InfallibleKey<#name> for #key_type {
+                type Value = #value_type;
+
+                fn get_infallible(map: &#name) -> &#value_type {
+                    #borrow
+                }
+
+                fn get_infallible_mut(map: &mut #name) -> &mut #value_type {
+                    #borrow_mut
+                }
+
+                fn insert_infallible(map: &mut #name, value: #value_type) -> ::core::option::Option<#value_type> {
+                    #store
+                }
+            }),
+        );
+    }
+
+    // Implement default, for convenience.  Fallible maps don't get one, since there's nothing to do with the error.
+    if map.parsed_attrs.fallible.is_none() {
+        impls.push(quote!(
+            impl ::core::default::Default for #name {
+                fn default() -> Self { Self::new() }
+            }
+        ));
+    }
+
+    // Typemap forwards to the inherent methods, except that it can't carry the bounds needed to build new dynamic
+    // entries in maps which iterate by traits.  Those maps go straight to the fixed part instead.
+    let trait_insert = if map.parsed_attrs.dynamic && map.additional_key_constraints.is_empty() {
+        quote!(self.insert_value::<K, K>(value))
+    } else {
+        quote!(match self.__fixed_typemap_insert_fixed::<K, K>(value) {
+            ::core::result::Result::Ok(old) => ::core::result::Result::Ok(old),
+            ::core::result::Result::Err(mut value) => match self.get_mut_ptr::<K, K>() {
+                ::core::option::Option::Some(x) => {
+                    ::core::mem::swap(&mut value, unsafe { &mut *(x as *mut K) });
+                    ::core::result::Result::Ok(::core::option::Option::Some(value))
+                }
+                ::core::option::Option::None => ::core::result::Result::Err(()),
+            },
+        })
+    };
+    impls.push(quote!(
+        impl #krate::Typemap for #name {
+            fn get<K: ::core::any::Any>(&self) -> ::core::option::Option<&K> {
+                self.get_const_ptr::<K, K>()
+                    .map(|x| unsafe { &*(x as *const K) })
+            }
+
+            fn get_mut<K: ::core::any::Any>(&mut self) -> ::core::option::Option<&mut K> {
+                self.get_mut_ptr::<K, K>()
+                    .map(|x| unsafe { &mut *(x as *mut K) })
+            }
+
+            #[allow(unused_mut)]
+            fn insert<K: ::core::any::Any>(&mut self, mut value: K) -> ::core::result::Result<::core::option::Option<K>, ()> {
+                #trait_insert
+            }
+
+            fn contains<K: ::core::any::Any>(&self) -> bool {
+                #name::contains::<K>(self)
+            }
+
+            fn len(&self) -> usize {
+                #name::len(self)
+            }
+        }
+    ));
+
+    impls.push(build_key_uniqueness_check(map));
+    impls.push(build_dyn_typemap_impl(map));
+    impls.push(build_boxed_collection_impls(map));
+
+    let constraints = &map.additional_key_constraints;
+    impls.push(quote!(
+        impl<K: ::core::any::Any + #(#constraints)+*> #krate::CanInsert<K> for #name {
+            fn try_insert(&mut self, value: K) -> ::core::result::Result<::core::option::Option<K>, ()> {
+                #name::insert(self, value)
+            }
+        }
+    ));
+
+    // Now punch out the IterableAs.
+    for (trait_path, method_name) in map.parsed_attrs.iterable_traits.iter() {
+        let const_iter_type = build_iter_type(map, trait_path, false, quote!('a));
+        let mut_iter_type = build_iter_type(map, trait_path, true, quote!('a));
+        let method_name_mut = quote::format_ident!("{}_mut", method_name);
+
+        impls.push(quote!(
+            impl<'a> #krate::IterableAs<'a, #name> for dyn #trait_path + 'a {
+                type Iter = #const_iter_type;
+                type IterMut = #mut_iter_type;
+
+                fn iter_as(map: &'a #name) -> Self::Iter {
+                    map.#method_name()
+                }
+
+                fn iter_mut_as(map: &'a mut #name) -> Self::IterMut {
+                    map.#method_name_mut()
+                }
+            }
+        ));
+
+        let get_as = get_as_method_name(trait_path);
+        let get_as_mut = quote::format_ident!("{}_mut", get_as);
+        impls.push(quote!(
+            impl<'a> #krate::GettableAs<'a, #name> for dyn #trait_path + 'a {
+                fn get_as(map: &'a #name, key: ::core::any::TypeId) -> ::core::option::Option<&'a Self> {
+                    map.#get_as(key)
+                }
+
+                fn get_as_mut(map: &'a mut #name, key: ::core::any::TypeId) -> ::core::option::Option<&'a mut Self> {
+                    map.#get_as_mut(key)
+                }
+            }
+        ));
+    }
+
+    quote!(#(#impls)*)
+}
+
+/// Build a check that keys which are different types to the macro, e.g. a type and an alias of it, are different types
+/// to the compiler as well.
+///
+/// Each key implements a trait named for what went wrong, so that a duplicate shows up as conflicting implementations
+/// of that trait, pointing at the keys.
+fn build_key_uniqueness_check(map: &Map) -> TokenStream2 {
+    let trait_name = quote::format_ident!("EachKeyOf{}MustBeADistinctType", map.name);
+    let impls = map.entries.iter().map(|e| {
+        let key = &e.key_type;
+        let cfgs = &e.cfgs;
+        quote_spanned!(syn::spanned::Spanned::span(key) => #(#cfgs)* impl #trait_name for #key {})
+    });
+
+    quote!(
+        const _: () = {
+            trait #trait_name {}
+            #(#impls)*
+        };
+    )
+}
+
+/// Build the methods which work through `TypeId` and `Box<dyn Any>`, for code which only knows types at runtime.
+fn build_boxed_access(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let key_enum = &map.key_enum_name;
+    let mut get_clauses = vec![];
+    let mut get_mut_clauses = vec![];
+    let mut insert_clauses = vec![];
+
+    for e in map.entries.iter() {
+        let key = &e.key_type;
+        let borrow = e.borrow(map, quote!(self), false);
+        let borrow_mut = e.borrow(map, quote!(self), true);
+        let cfgs = &e.cfgs;
+
+        get_clauses.push(quote!(
+            #(#cfgs)*
+            if key == ::core::any::TypeId::of::<#key>() {
+                return ::core::option::Option::Some(#borrow as &dyn ::core::any::Any);
+            }
+        ));
+        get_mut_clauses.push(quote!(
+            #(#cfgs)*
+            if key == ::core::any::TypeId::of::<#key>() {
+                return ::core::option::Option::Some(#borrow_mut as &mut dyn ::core::any::Any);
+            }
+        ));
+
+        // A boxed value is keyed by its own type, so entries with marker keys can't be inserted this way.
+        if e.value_type.is_none() {
+            let unwrapper = fast_unwrap(quote!(value.downcast::<#key>().ok()));
+            let store = e.store(quote!(self), quote!(*#unwrapper));
+            insert_clauses.push(quote!(
+                #(#cfgs)*
+                if id == ::core::any::TypeId::of::<#key>() {
+                    let old = #store;
+                    return ::core::result::Result::Ok(old.map(|old| ::std::boxed::Box::new(old) as ::std::boxed::Box<dyn ::core::any::Any>));
+                }
+            ));
+        }
+    }
+
+    let mut dyn_get = quote!(::core::option::Option::None);
+    let mut dyn_get_mut = quote!(::core::option::Option::None);
+    let mut dyn_insert = quote!(::core::result::Result::Err(value));
+    if map.parsed_attrs.dynamic {
+        let df = &map.dynamic_field_name;
+        let dc = &map.dynamic_cell_name;
+        dyn_get = quote!(self.#df.get(&key).map(|x| &*x.value));
+        dyn_get_mut = quote!(self.#df.get_mut(&key).map(|x| &mut *x.value));
+
+        // Without iterable traits, the cell is only the value.  With them, we'd need the casts and can't build them, but
+        // a cell which already holds a value of the same type has the right ones.
+        let vacant = if map.additional_key_constraints.is_empty() {
+            quote!(::std::collections::hash_map::Entry::Vacant(e) => {
+                e.insert(#dc { value, type_name: ::core::option::Option::None });
+                ::core::result::Result::Ok(::core::option::Option::None)
+            })
+        } else {
+            quote!(::std::collections::hash_map::Entry::Vacant(_) => ::core::result::Result::Err(value))
+        };
+        dyn_insert = quote!(
+            match self.#df.entry(id) {
+                ::std::collections::hash_map::Entry::Occupied(mut e) => {
+                    // This key might hold some other type, if the value was inserted with a marker key.
+                    if (*e.get().value).type_id() != id {
+                        return ::core::result::Result::Err(value);
+                    }
+                    ::core::result::Result::Ok(::core::option::Option::Some(::core::mem::replace(&mut e.get_mut().value, value)))
+                }
+                #vacant
+            }
+        );
+    }
+
+    quote!(
+        /// Get the value stored under the key with the given `TypeId`.
+        ///
+        /// For entries declared as `Key => Value`, this is the id of the key and the result is the value.
+        #vis fn get_by_type_id(&self, key: ::core::any::TypeId) -> ::core::option::Option<&dyn ::core::any::Any> {
+            #(#get_clauses)*
+            #dyn_get
+        }
+
+        /// Get a mutable reference to the value stored under the key with the given `TypeId`.
+        #vis fn get_by_type_id_mut(&mut self, key: ::core::any::TypeId) -> ::core::option::Option<&mut dyn ::core::any::Any> {
+            #(#get_mut_clauses)*
+            #dyn_get_mut
+        }
+
+        /// Insert a boxed value, keyed by its own type.
+        ///
+        /// Values whose type is a fixed entry replace that entry.  Returns the old value if there was one.  If the value
+        /// can't be inserted it is handed back as the error, which happens in the same cases as for `insert` and
+        /// additionally when the map iterates by traits and the type is new, since there's no way to cast it.
+        #vis fn insert_boxed(
+            &mut self,
+            value: ::std::boxed::Box<dyn ::core::any::Any>,
+        ) -> ::core::result::Result<::core::option::Option<::std::boxed::Box<dyn ::core::any::Any>>, ::std::boxed::Box<dyn ::core::any::Any>> {
+            use ::core::any::Any;
+
+            // Careful: the type id of the box itself would be that of the box.
+            let id = (*value).type_id();
+            #(#insert_clauses)*
+            // Entries declared as `Key => Value` are keyed by a type they don't hold, so this can't be one of them.
+            if #key_enum::from_type_id(id).is_some() {
+                return ::core::result::Result::Err(value);
+            }
+            #dyn_insert
+        }
+    )
+}
+
+/// Implement `DynTypemap`, which forwards to the methods from `build_boxed_access`.
+fn build_dyn_typemap_impl(map: &Map) -> TokenStream2 {
+    let krate = &map.crate_path;
+    let name = &map.name;
+    let type_ids = map.entries.iter().map(|e| {
+        let key = &e.key_type;
+        let cfgs = &e.cfgs;
+        quote!(#(#cfgs)* ids.push(::core::any::TypeId::of::<#key>());)
+    });
+
+    let mut dyn_type_ids = quote!();
+    if map.parsed_attrs.dynamic {
+        let df = &map.dynamic_field_name;
+        dyn_type_ids = quote!(::core::iter::Extend::extend(&mut ids, ::core::iter::Iterator::copied(self.#df.keys())););
+    }
+
+    quote!(
+        impl #krate::DynTypemap for #name {
+            fn get_dyn(&self, key: ::core::any::TypeId) -> ::core::option::Option<&dyn ::core::any::Any> {
+                self.get_by_type_id(key)
+            }
+
+            fn get_dyn_mut(&mut self, key: ::core::any::TypeId) -> ::core::option::Option<&mut dyn ::core::any::Any> {
+                self.get_by_type_id_mut(key)
+            }
+
+            fn insert_boxed(
+                &mut self,
+                value: ::std::boxed::Box<dyn ::core::any::Any>,
+            ) -> ::core::result::Result<::core::option::Option<::std::boxed::Box<dyn ::core::any::Any>>, ::std::boxed::Box<dyn ::core::any::Any>> {
+                #name::insert_boxed(self, value)
+            }
+
+            fn type_ids(&self) -> ::std::vec::Vec<::core::any::TypeId> {
+                #[allow(unused_mut)]
+                let mut ids = ::std::vec::Vec::new();
+                #(#type_ids)*
+                #dyn_type_ids
+                ids
+            }
+        }
+    )
+}
+
+/// Implement `Extend` and `FromIterator` over boxed values, which go through `insert_boxed`.
+fn build_boxed_collection_impls(map: &Map) -> TokenStream2 {
+    let name = &map.name;
+
+    // Fallible maps can't be built without a way to report the error.
+    let from_iter = if map.parsed_attrs.fallible.is_none() {
+        quote!(
+            impl ::core::iter::FromIterator<::std::boxed::Box<dyn ::core::any::Any>> for #name {
+                fn from_iter<I: ::core::iter::IntoIterator<Item = ::std::boxed::Box<dyn ::core::any::Any>>>(iter: I) -> Self {
+                    let mut map = Self::new();
+                    ::core::iter::Extend::extend(&mut map, iter);
+                    map
+                }
+            }
+        )
+    } else {
+        quote!()
+    };
+
+    quote!(
+        /// Inserts each value with `insert_boxed`.
+        ///
+        /// Values which `insert_boxed` would refuse are dropped without any indication: those of types which the map
+        /// can't hold, types used as marker keys, and in maps which iterate by traits, types not already in the dynamic
+        /// section.  Use `insert_boxed` directly to find out about them.
+        impl ::core::iter::Extend<::std::boxed::Box<dyn ::core::any::Any>> for #name {
+            fn extend<I: ::core::iter::IntoIterator<Item = ::std::boxed::Box<dyn ::core::any::Any>>>(&mut self, iter: I) {
+                for value in iter {
+                    let _ = self.insert_boxed(value);
+                }
+            }
+        }
+
+        #from_iter
+    )
+}
+
+fn build_constructors(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    // Entries are built into locals in declaration order, so that initializers can borrow entries which came before
+    // them by name.  Forward references were already rejected by check_initializer_references.
+    let mut locals = vec![];
+    let mut joined_fields = vec![];
+
+    for e in map.entries.iter() {
+        let name = e.name.as_ref().unwrap();
+        let cfgs = &e.cfgs;
+        if e.is_lazy() {
+            let cell = lazy_cell_type(map);
+            joined_fields.push(quote!(#(#cfgs)* #name: #cell::new()));
+            continue;
+        }
+
+        // Boxing happens only once everything is built, so that later initializers see the value itself.
+        let value_type = e.value_type();
+        let initializer = &e.initializer;
+        locals.push(quote!(#(#cfgs)* let #name: #value_type = #initializer;));
+        if e.is_boxed() {
+            let wrapped = e.wrap_value(quote!(#name));
+            joined_fields.push(quote!(#(#cfgs)* #name: #wrapped));
+        } else {
+            joined_fields.push(quote!(#(#cfgs)* #name));
+        }
+    }
+
+    if map.parsed_attrs.dynamic {
+        let dn = &map.dynamic_field_name;
+        joined_fields.push(quote!(#dn: ::core::default::Default::default()));
+    }
+
+    if let Some(fallible) = map.parsed_attrs.fallible.as_ref() {
+        let error = &fallible.error.0;
+        // Since the entries are locals, an early return drops everything built so far.
+        return quote!(
+            /// Try to construct a new typemap.
+            ///
+            /// All fixed fields will have their specified default value, and are initialized in declaration order.
+            /// Initializers may use `?`, and if one fails then the entries built so far are dropped and the error is
+            /// returned.  If there is a dynamic section, it will be empty.
+            #vis fn try_new() -> ::core::result::Result<Self, #error> {
+                #(#locals)*
+
+                ::core::result::Result::Ok(Self {
+                    #(#joined_fields),*
+                })
+            }
+        );
+    }
+
+    let maybe_const = if map.parsed_attrs.const_new {
+        quote!(const)
+    } else {
+        quote!()
+    };
+
+    quote!(
+        /// Construct a new typemap.
+        ///
+        /// All fixed fields will have their specified default value, and are initialized in declaration order.  If
+        /// there is a dynamic sectionb, it will be empty.
+        #vis #maybe_const fn new() -> Self {
+            #(#locals)*
+
+            Self {
+                #(#joined_fields),*
+            }
+        }
+    )
+}
+
+/// Build the low-level unsafe get methods.
+fn build_unsafe_getters(map: &Map) -> TokenStream2 {
+    let mut funcs = vec![];
+
+    // These are keyed by both the key and the value type.  For most entries they're the same, but entries declared as
+    // `Key => Value` and dynamic entries inserted through `insert_keyed` must only be found if both match, since the
+    // pointer is cast to the value type.
+    for (fname, is_mut) in [("get_const_ptr", false), ("get_mut_ptr", true)] {
+        let const_or_mut = if is_mut { quote!(mut) } else { quote!(const) };
+
+        let maybe_mut = if is_mut { quote!(mut) } else { quote!() };
+
+        let fident = quote::format_ident!("{}", fname);
+        let clauses = map
+            .entries
+            .iter()
+            .map(|e| {
+                let key = &e.key_type;
+                let value = e.value_type();
+                let borrowed = e.borrow(map, quote!(self), is_mut);
+                let cfgs = &e.cfgs;
+                quote!(#(#cfgs)* if ::core::any::TypeId::of::<K>() == ::core::any::TypeId::of::<#key>()
+                    && ::core::any::TypeId::of::<V>() == ::core::any::TypeId::of::<#value>() {
+                    return ::core::option::Option::Some(#borrowed as *#const_or_mut #value as *#const_or_mut u8);
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut final_clause = quote!(::core::option::Option::None);
+        if map.parsed_attrs.dynamic {
+            let suffix = if is_mut { "_mut" } else { "" };
+            let map_getter = quote::format_ident!("get{}", suffix);
+            let any_ref = quote::format_ident!("downcast_{}", if is_mut { "mut" } else { "ref" });
+            let df = &map.dynamic_field_name;
+
+            // The same key may hold a value of another type if it was inserted through a different method, so this
+            // downcast is checked.
+            final_clause = quote!({
+                self.#df.#map_getter(&::core::any::TypeId::of::<K>())
+                    .and_then(|x| (&#maybe_mut *x.value).#any_ref::<V>())
+                    .map(|x| x as *#const_or_mut V as *#const_or_mut u8)
+            });
+        }
+
+        funcs.push(quote!(
+            fn #fident<K: ::core::any::Any, V: ::core::any::Any>(&#maybe_mut self) -> ::core::option::Option<*#const_or_mut u8> {
+                use ::core::any::Any;
+
+                #(#clauses)*
+
+                #final_clause
+            }
+        ));
+    }
+
+    quote!(#(#funcs)*)
+}
+
+fn build_infallible_getters(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let mn = &map.name;
+    let additional_constraints = &map.additional_key_constraints;
+    quote!(
+        /// Get a value from the typemap which is guaranteed to be present.
+        ///
+        /// Your program won't compile if it's not.  Compiles down to a simple field borrow.
+        #[inline(always)]
+        #vis fn get_infallible<K: #krate::InfallibleKey<#mn>>(&self) -> &K::Value
+        where
+            K::Value: #(#additional_constraints)+*
+        {
+            K::get_infallible(self)
+        }
+
+        /// get a mutable reference to a type guaranteed to be in the typemap.
+        ///
+        /// If it's not, your program won't compile.
+        #[inline(always)]
+        #vis fn get_infallible_mut<K: #krate::InfallibleKey<#mn>>(&mut self) -> &mut K::Value
+        where
+            K::Value: #(#additional_constraints)+*
+        {
+            K::get_infallible_mut(self)
+        }
+    )
+}
+
+fn build_fallible_getters(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let additional_constraints = &map.additional_key_constraints;
+
+    // Answered from the types alone where possible, so that lazy entries aren't built just to say that they're there.
+    let mut contains_clauses = map
+        .entries
+        .iter()
+        .map(|e| {
+            let key = &e.key_type;
+            let value = e.value_type();
+            let cfgs = &e.cfgs;
+            quote!(#(#cfgs)* if ::core::any::TypeId::of::<K>() == ::core::any::TypeId::of::<#key>() {
+                return ::core::any::TypeId::of::<K>() == ::core::any::TypeId::of::<#value>();
+            })
+        })
+        .collect::<Vec<_>>();
+    if map.parsed_attrs.dynamic {
+        let df = &map.dynamic_field_name;
+        contains_clauses.push(quote!(
+            self.#df
+                .get(&::core::any::TypeId::of::<K>())
+                .map_or(false, |x| (*x.value).is::<K>())
+        ));
+    } else {
+        contains_clauses.push(quote!(false));
+    }
+
+    quote!(
+        /// Try to get a type from the typemap.
+        #[inline(always)]
+        #vis fn get<K: ::core::any::Any + #(#additional_constraints)+*>(&self) -> ::core::option::Option<&K> {
+            self.get_const_ptr::<K, K>()
+                .map(|x| unsafe { &*(x as *const K) })
+        }
+
+        /// Try to get a mutable reference to a value in the typemap.
+        #[inline(always)]
+        #vis fn get_mut<K: ::core::any::Any + #(#additional_constraints)+*>(&mut self) -> ::core::option::Option<&mut K> {
+            self.get_mut_ptr::<K, K>()
+                .map(|x| unsafe { &mut *(x as *mut K) })
+        }
+
+        /// Whether the typemap contains a value of the given type.
+        #vis fn contains<K: ::core::any::Any>(&self) -> bool {
+            #(#contains_clauses)*
+        }
+
+        /// Try to get the value stored for a `TypemapKey`, which may be of a different type than the key.
+        #[inline(always)]
+        #vis fn get_keyed<K: #krate::TypemapKey>(&self) -> ::core::option::Option<&K::Value>
+        where
+            K::Value: #(#additional_constraints)+*
+        {
+            self.get_const_ptr::<K, K::Value>()
+                .map(|x| unsafe { &*(x as *const K::Value) })
+        }
+
+        /// Try to get a mutable reference to the value stored for a `TypemapKey`.
+        #[inline(always)]
+        #vis fn get_keyed_mut<K: #krate::TypemapKey>(&mut self) -> ::core::option::Option<&mut K::Value>
+        where
+            K::Value: #(#additional_constraints)+*
+        {
+            self.get_mut_ptr::<K, K::Value>()
+                .map(|x| unsafe { &mut *(x as *mut K::Value) })
+        }
+    )
+}
+
+fn build_len(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let fixed_len = fixed_len(map);
+    let mut len = quote!(#fixed_len);
+    if map.parsed_attrs.dynamic {
+        let df = &map.dynamic_field_name;
+        // Bound first, since a block at the start of a statement wouldn't parse as an operand.
+        len = quote!(
+            let fixed = #fixed_len;
+            fixed + self.#df.len()
+        );
+    }
+
+    quote!(
+        /// The number of values in the typemap.  Fixed entries are always present, so this is never less than the
+        /// number of them.
+        #vis fn len(&self) -> usize {
+            #len
+        }
+
+        /// Whether the typemap contains no values at all, which can only happen if it has no fixed entries.
+        #vis fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+    )
+}
+
+fn build_insert(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let additional_constraints = &map.additional_key_constraints;
+
+    let fixed_clauses = map
+        .entries
+        .iter()
+        .map(|e| {
+            let key = &e.key_type;
+            let value_type = e.value_type();
+            let store = e.store(quote!(self), quote!(value));
+            let cfgs = &e.cfgs;
+            // The types were just checked to be the same, so these reads only change the type the compiler sees.
+            quote!(#(#cfgs)* if ::core::any::TypeId::of::<K>() == ::core::any::TypeId::of::<#key>()
+                && ::core::any::TypeId::of::<V>() == ::core::any::TypeId::of::<#value_type>() {
+                let value = ::core::mem::ManuallyDrop::new(value);
+                let value = unsafe { ::core::ptr::read(&*value as *const V as *const #value_type) };
+                let old = #store;
+                return ::core::result::Result::Ok(old.map(|old| {
+                    let old = ::core::mem::ManuallyDrop::new(old);
+                    unsafe { ::core::ptr::read(&*old as *const #value_type as *const V) }
+                }));
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut dynamic_clause = quote!(
+        ::core::mem::drop(value);
+        ::core::result::Result::Err(())
+    );
+    if map.parsed_attrs.dynamic {
+        let df = &map.dynamic_field_name;
+        let dc = &map.dynamic_cell_name;
+        // If the key is present here but holds a value of some other type, we refuse to replace it.
+        dynamic_clause = quote!(
+            match self.#df.entry(::core::any::TypeId::of::<K>()) {
+                ::std::collections::hash_map::Entry::Occupied(mut e) => match (*e.get_mut().value).downcast_mut::<V>() {
+                    ::core::option::Option::Some(x) => ::core::result::Result::Ok(::core::option::Option::Some(::core::mem::replace(x, value))),
+                    ::core::option::Option::None => ::core::result::Result::Err(()),
+                },
+                ::std::collections::hash_map::Entry::Vacant(e) => {
+                    e.insert(#dc::new(::core::any::type_name::<K>(), value));
+                    ::core::result::Result::Ok(::core::option::Option::None)
+                }
+            }
+        );
+    }
 
-    let dynamic_part = if map.parsed_attrs.dynamic {
-        // If the array is dynamic, we need the iterator from the hashmap, which is a map over the values to convert
-        // from the cell type to the dynamic reference.
-        let celltype = &map.dynamic_cell_name;
-        let map_iter_type = if is_mut {
-            quote!(ValuesMut)
+    quote!(
+        /// Store a value of type `V` in the fixed entry keyed by `K`, or give it back if there isn't one.
+        ///
+        /// Prefixed so that it can't collide with accessors, which are named after fields and types.
+        fn __fixed_typemap_insert_fixed<K: ::core::any::Any, V: ::core::any::Any>(&mut self, value: V) -> ::core::result::Result<::core::option::Option<V>, V> {
+            #(#fixed_clauses)*
+            ::core::result::Result::Err(value)
+        }
+
+        /// Insert a value of type `V` under the key `K`, which is the shared implementation of the various inserts.
+        fn insert_value<K: ::core::any::Any, V: ::core::any::Any + #(#additional_constraints)+*>(&mut self, value: V) -> ::core::result::Result<::core::option::Option<V>, ()> {
+            let value = match self.__fixed_typemap_insert_fixed::<K, V>(value) {
+                ::core::result::Result::Ok(old) => return ::core::result::Result::Ok(old),
+                ::core::result::Result::Err(value) => value,
+            };
+            // A fixed entry with this key holds some other type, and the key can't also be used in the dynamic section.
+            if Self::index_of::<K>().is_some() {
+                return ::core::result::Result::Err(());
+            }
+
+            #dynamic_clause
+        }
+
+        /// Try to insert into the typemap.
+        ///
+        /// Like the std collections, inserting a value that's already in the map returns `Some(old_value)` and updates
+        /// it. Errors if the typemap is fixed and the type provided isn't present, or if the type is already used as a
+        /// key for a value of some other type.
+        #vis fn insert<K: ::core::any::Any + #(#additional_constraints)+*>(&mut self, value: K) -> ::core::result::Result<::core::option::Option<K>, ()> {
+            self.insert_value::<K, K>(value)
+        }
+
+        /// Try to insert the value for a `TypemapKey`.
+        ///
+        /// This is like `insert`, except that the value is stored under the key `K` rather than its own type.
+        #vis fn insert_keyed<K: #krate::TypemapKey>(&mut self, value: K::Value) -> ::core::result::Result<::core::option::Option<K::Value>, ()>
+        where
+            K::Value: #(#additional_constraints)+*
+        {
+            self.insert_value::<K, K::Value>(value)
+        }
+
+        /// Insert into the typemap where the key is known to be in the typemap at the type system level.
+        #vis fn insert_infallible<K: #krate::InfallibleKey<Self>>(&mut self, value: K::Value) -> ::core::option::Option<K::Value> {
+            K::insert_infallible(self, value)
+        }
+    )
+}
+
+fn build_iterators(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let mut methods = vec![];
+
+    for (trait_path, name) in map.parsed_attrs.iterable_traits.iter() {
+        for is_mut in [false, true] {
+            let method_name = quote::format_ident!("{}{}", name, if is_mut { "_mut" } else { "" });
+            let cell_type = &map.dynamic_cell_name;
+            let maybe_mut = if is_mut { quote!(mut) } else { quote!() };
+            let iter_fn = quote::format_ident!("values{}", if is_mut { "_mut" } else { "" });
+            let return_type = build_iter_type(map, trait_path, is_mut, quote!('_));
+
+            // This works by having two iterators that we chain.  The first is a fixed-sized array which consists of the
+            // non-dynamic fields pre-cast to the trait object.  The second consists of a map over the cell type, using
+            // the inline function pointers therein to convert to the trait object as needed.
+            //
+            // If uninitialized lazy entries are to be skipped or some entries are behind `cfg`, the array is instead of
+            // options and gets flattened.
+            let skips = iteration_skips_entries(map);
+            let uses_options = iteration_uses_options(map);
+            let static_fields = map
+                .entries
+                .iter()
+                .map(|e| {
+                    let elem = if skips {
+                        let borrowed = e.borrow_if_initialized(map, quote!(self), is_mut);
+                        quote!(#borrowed.map(|x| x as &#maybe_mut dyn #trait_path))
+                    } else {
+                        let borrowed = e.borrow(map, quote!(self), is_mut);
+                        quote!(#borrowed as &#maybe_mut dyn #trait_path)
+                    };
+
+                    if !uses_options {
+                        return elem;
+                    }
+                    let elem = if skips { elem } else { quote!(::core::option::Option::Some(#elem)) };
+                    if e.cfgs.is_empty() {
+                        return elem;
+                    }
+
+                    // The entry shadows the `None` if it's configured in.
+                    let cfgs = &e.cfgs;
+                    quote!({
+                        #[allow(unused_variables)]
+                        let entry: ::core::option::Option<&#maybe_mut dyn #trait_path> = ::core::option::Option::None;
+                        #(#cfgs)*
+                        let entry = #elem;
+                        entry
+                    })
+                })
+                .collect::<Vec<_>>();
+            let static_fields_len = static_fields.len();
+            let static_iter = quote!(::core::iter::IntoIterator::into_iter(static_arr));
+            let (static_elem, static_iter) = if uses_options {
+                (
+                    quote!(::core::option::Option<&#maybe_mut dyn #trait_path>),
+                    quote!(::core::iter::Iterator::flatten(#static_iter)),
+                )
+            } else {
+                (quote!(&#maybe_mut dyn #trait_path), static_iter)
+            };
+
+            let mut dynamic_part = quote!(let dyn_iter = ::core::iter::empty(););
+            if map.parsed_attrs.dynamic {
+                let df = &map.dynamic_field_name;
+                dynamic_part = quote!(
+                    let dyn_ref = &#maybe_mut self.#df;
+                    // We need a function which can be used as a function pointer to convert, as well as the map.  This
+                    // makes it possible to name the return type.
+                    fn conv<'b>(cell: &'b #maybe_mut #cell_type) -> &'b #maybe_mut dyn #trait_path {
+                        (cell.#method_name)(&#maybe_mut *cell.value)
+                    }
+
+                    let dyn_iter = ::core::iter::Iterator::map(dyn_ref.#iter_fn(), conv as fn(&#maybe_mut #cell_type) -> &#maybe_mut dyn #trait_path);
+                )
+            }
+
+            methods.push(quote!(
+                #vis fn #method_name(&#maybe_mut self) -> #return_type {
+                    let static_arr: [#static_elem; #static_fields_len] = [#(#static_fields),*];
+                    let static_iter = #static_iter;
+                    #dynamic_part
+                    ::core::iter::Iterator::chain(static_iter, dyn_iter)
+                }
+            ));
+        }
+    }
+
+    quote!(#(#methods)*)
+}
+
+/// Build the `get_as_` methods, which look up one entry by `TypeId` as a trait object.
+///
+/// These use the same casts as iteration: fixed entries are cast directly, and the dynamic section goes through the
+/// function pointers in the cell.
+fn build_trait_getters(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let mut methods = vec![];
+
+    for (trait_path, iter_name) in map.parsed_attrs.iterable_traits.iter() {
+        let method_name = get_as_method_name(trait_path);
+        for is_mut in [false, true] {
+            let maybe_mut = if is_mut { quote!(mut) } else { quote!() };
+            let method_name =
+                quote::format_ident!("{}{}", method_name, if is_mut { "_mut" } else { "" });
+            let cast_name =
+                quote::format_ident!("{}{}", iter_name, if is_mut { "_mut" } else { "" });
+
+            let clauses = map.entries.iter().map(|e| {
+                let key = &e.key_type;
+                let borrowed = e.borrow(map, quote!(self), is_mut);
+                let cfgs = &e.cfgs;
+                quote!(
+                    #(#cfgs)*
+                    if key == ::core::any::TypeId::of::<#key>() {
+                        return ::core::option::Option::Some(#borrowed as &#maybe_mut dyn #trait_path);
+                    }
+                )
+            });
+
+            let mut dynamic_part = quote!(::core::option::Option::None);
+            if map.parsed_attrs.dynamic {
+                let df = &map.dynamic_field_name;
+                let get_fn = if is_mut { quote!(get_mut) } else { quote!(get) };
+                dynamic_part = quote!(self.#df.#get_fn(&key).map(|cell| (cell.#cast_name)(&#maybe_mut *cell.value)));
+            }
+
+            let doc = if is_mut {
+                "Get a mutable reference to the value stored under the key with the given `TypeId` as a trait object."
+            } else {
+                "Get the value stored under the key with the given `TypeId` as a trait object."
+            };
+
+            methods.push(quote!(
+                #[doc = #doc]
+                #vis fn #method_name(&#maybe_mut self, key: ::core::any::TypeId) -> ::core::option::Option<&#maybe_mut dyn #trait_path> {
+                    #(#clauses)*
+                    #dynamic_part
+                }
+            ));
+        }
+    }
+
+    quote!(#(#methods)*)
+}
+
+/// Build the accessor methods, which get entries declared as `_` by name.
+fn build_accessors(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let mut methods = vec![];
+
+    for e in map.entries.iter() {
+        let accessor = match e.accessor() {
+            Some(a) => a,
+            None => continue,
+        };
+        let accessor_mut = quote::format_ident!("{}_mut", accessor);
+        let value_type = e.value_type();
+        let borrow = e.borrow(map, quote!(self), false);
+        let borrow_mut = e.borrow(map, quote!(self), true);
+        let cfgs = &e.cfgs;
+
+        // Plain entries are just a field, which can be borrowed in const contexts.
+        let maybe_const = if e.is_lazy() || e.is_boxed() {
+            quote!()
         } else {
-            quote!(Values)
+            quote!(const)
         };
 
-        quote!(core::iter::Map<std::collections::hash_map::#map_iter_type<#lt, core::any::TypeId, #celltype>, for<'r> fn(&'r #maybe_mut #celltype) -> &'r #maybe_mut (dyn #trait_name + 'r)>)
+        let key = &e.key_type;
+        let doc = format!("Get the `{}` entry.", quote!(#key));
+        let doc_mut = format!("Get a mutable reference to the `{}` entry.", quote!(#key));
+
+        methods.push(quote!(
+            #(#cfgs)*
+            #[doc = #doc]
+            #vis #maybe_const fn #accessor(&self) -> &#value_type {
+                #borrow
+            }
+
+            #(#cfgs)*
+            #[doc = #doc_mut]
+            #vis #maybe_const fn #accessor_mut(&mut self) -> &mut #value_type {
+                #borrow_mut
+            }
+        ));
+    }
+
+    quote!(#(#methods)*)
+}
+
+/// Build the enum with a variant per fixed entry, in declaration order, and its conversions.
+fn build_key_enum(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let name = &map.key_enum_name;
+    let map_name = &map.name;
+    let len = fixed_len(map);
+
+    let mut variants = vec![];
+    let mut name_arms = vec![];
+    let mut from_name_arms = vec![];
+    let mut type_name_arms = vec![];
+    let mut type_id_arms = vec![];
+    let mut from_type_id_clauses = vec![];
+    let mut all_elems = vec![];
+    let mut all_writes = vec![];
+
+    for e in map.entries.iter() {
+        let variant = e.variant();
+        let key = &e.key_type;
+        let cfgs = &e.cfgs;
+        let doc = format!("The `{}` entry.", quote!(#key));
+
+        variants.push(quote!(#(#cfgs)* #[doc = #doc] #variant));
+        let field = e.field_name();
+        name_arms.push(quote!(#(#cfgs)* Self::#variant => #field));
+        from_name_arms
+            .push(quote!(#(#cfgs)* #field => ::core::option::Option::Some(Self::#variant)));
+        type_name_arms.push(quote!(#(#cfgs)* Self::#variant => ::core::any::type_name::<#key>()));
+        type_id_arms.push(quote!(#(#cfgs)* Self::#variant => ::core::any::TypeId::of::<#key>()));
+        from_type_id_clauses.push(quote!(
+            #(#cfgs)*
+            if id == ::core::any::TypeId::of::<#key>() {
+                return ::core::option::Option::Some(Self::#variant);
+            }
+        ));
+        all_elems.push(quote!(Self::#variant));
+        all_writes.push(quote!(
+            #(#cfgs)*
+            {
+                unsafe { ptr.add(i).write(Self::#variant) };
+                i += 1;
+            }
+        ));
+    }
+
+    // Attributes aren't allowed on array elements, so if entries can be configured out the array is filled in one at a
+    // time.  This happens at compile time, where a mistake is an error rather than undefined behavior.
+    let all = if map.entries.iter().all(|e| e.cfgs.is_empty()) {
+        quote!([#(#all_elems),*])
     } else {
-        // Otherwise, it's the empty iterator.
-        quote!(core::iter::Empty<&#lt #maybe_mut dyn #trait_name>)
+        quote!({
+            let mut all = ::core::mem::MaybeUninit::<[Self; #len]>::uninit();
+            let ptr = all.as_mut_ptr() as *mut Self;
+            let mut i = 0usize;
+            #(#all_writes)*
+            let _ = i;
+            unsafe { all.assume_init() }
+        })
     };
 
-    // The result is the chain of these types.
-    quote!(core::iter::Chain<#static_part, #dynamic_part>)
-}
+    let doc = format!(
+        "The fixed entries of [`{}`], for when a value is needed to name one, such as in a `match` or a message.",
+        map_name
+    );
 
-/// Implement all the traits we want to implement.
-fn build_trait_impls(map: &Map) -> TokenStream2 {
-    let name = &map.name;
+    quote!(
+        #[doc = #doc]
+        #[derive(
+            ::core::clone::Clone,
+            ::core::marker::Copy,
+            ::core::fmt::Debug,
+            ::core::cmp::PartialEq,
+            ::core::cmp::Eq,
+            ::core::cmp::PartialOrd,
+            ::core::cmp::Ord,
+            ::core::hash::Hash,
+        )]
+        #vis enum #name {
+            #(#variants),*
+        }
 
-    let mut impls = vec![];
+        impl #name {
+            /// Every entry, in the order they were declared.
+            #vis const ALL: [Self; #len] = #all;
 
-    for e in map.entries.iter() {
-        let key_type = &e.key_type;
-        let field_name = e.name.as_ref().unwrap();
-        impls.push(
-            quote!(unsafe impl fixed_typemap_internals::InfallibleKey<#name> for #key_type {
-                fn get_infallible(map: &#name) -> &#key_type {
-                    &map.#field_name
+            /// The position of this entry in `ALL`.
+            #vis const fn index(self) -> usize {
+                self as usize
+            }
+
+            /// The name of the field holding this entry.
+            #vis const fn name(self) -> &'static str {
+                match self {
+                    #(#name_arms),*
+                }
+            }
+
+            /// Find the entry held by the field with the given name, if there is one.
+            #vis fn from_name(name: &str) -> ::core::option::Option<Self> {
+                match name {
+                    #(#from_name_arms,)*
+                    _ => ::core::option::Option::None,
                 }
+            }
 
-                fn get_infallible_mut(map: &mut #name) -> &mut #key_type {
-                    &mut map.#field_name
+            /// The name of the key type of this entry, from `core::any::type_name`.
+            #vis fn type_name(self) -> &'static str {
+                match self {
+                    #(#type_name_arms),*
                 }
+            }
 
-                fn insert_infallible(map: &mut #name, mut value: Self) -> Option<Self> {
-                    let dest = Self::get_infallible_mut(map);
-                    core::mem::swap(&mut value, dest);
-                    Some(value)
+            /// The `TypeId` of the key type of this entry.
+            #vis fn type_id(self) -> ::core::any::TypeId {
+                match self {
+                    #(#type_id_arms),*
                 }
-            }),
+            }
+
+            /// Find the entry keyed by the type with the given `TypeId`, if there is one.
+            #vis fn from_type_id(id: ::core::any::TypeId) -> ::core::option::Option<Self> {
+                #(#from_type_id_clauses)*
+                ::core::option::Option::None
+            }
+        }
+
+        impl ::core::convert::From<#name> for ::core::any::TypeId {
+            fn from(key: #name) -> Self {
+                key.type_id()
+            }
+        }
+
+        impl ::core::convert::TryFrom<::core::any::TypeId> for #name {
+            /// The id, handed back because it isn't the id of a fixed entry.
+            type Error = ::core::any::TypeId;
+
+            fn try_from(id: ::core::any::TypeId) -> ::core::result::Result<Self, Self::Error> {
+                match Self::from_type_id(id) {
+                    ::core::option::Option::Some(key) => ::core::result::Result::Ok(key),
+                    ::core::option::Option::None => ::core::result::Result::Err(id),
+                }
+            }
+        }
+    )
+}
+
+/// Build `index_of` and `get_by_index`, which address fixed entries by their position in the key enum.
+fn build_index_access(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let key_enum = &map.key_enum_name;
+    let mut arms = vec![];
+    let mut arms_mut = vec![];
+
+    for e in map.entries.iter() {
+        let variant = e.variant();
+        let borrow = e.borrow(map, quote!(self), false);
+        let borrow_mut = e.borrow(map, quote!(self), true);
+        let cfgs = &e.cfgs;
+        // Bound first, since borrowing a lazy entry is a block, which can't start an `as` expression in a match arm.
+        arms.push(quote!(#(#cfgs)* #key_enum::#variant => {
+            let value: &dyn ::core::any::Any = #borrow;
+            value
+        }));
+        arms_mut.push(quote!(#(#cfgs)* #key_enum::#variant => {
+            let value: &mut dyn ::core::any::Any = #borrow_mut;
+            value
+        }));
+    }
+
+    let doc = format!(
+        "The position of the fixed entry keyed by `K`, which is its index in [`{}::ALL`].",
+        key_enum
+    );
+
+    quote!(
+        #[doc = #doc]
+        ///
+        /// Returns `None` if `K` isn't the key of a fixed entry, including if it's in the dynamic section.
+        #vis fn index_of<K: ?::core::marker::Sized + 'static>() -> ::core::option::Option<usize> {
+            #key_enum::from_type_id(::core::any::TypeId::of::<K>()).map(#key_enum::index)
+        }
+
+        /// Get the value of the fixed entry at the given position, as from `index_of`.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the index is past the last fixed entry.
+        #vis fn get_by_index(&self, index: usize) -> &dyn ::core::any::Any {
+            match #key_enum::ALL[index] {
+                #(#arms),*
+            }
+        }
+
+        /// Get a mutable reference to the value of the fixed entry at the given position, as from `index_of`.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the index is past the last fixed entry.
+        #vis fn get_by_index_mut(&mut self, index: usize) -> &mut dyn ::core::any::Any {
+            match #key_enum::ALL[index] {
+                #(#arms_mut),*
+            }
+        }
+    )
+}
+
+/// Build `get_by_name` and `names`, which address entries by field name or by the name of their type.
+fn build_name_access(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let key_enum = &map.key_enum_name;
+
+    let mut dyn_get = quote!(::core::option::Option::None);
+    let mut dyn_get_mut = quote!(::core::option::Option::None);
+    let mut dyn_names = quote!(::core::iter::empty());
+    if map.parsed_attrs.dynamic {
+        let df = &map.dynamic_field_name;
+        let find = quote!(|cell| cell.type_name == ::core::option::Option::Some(name));
+        dyn_get = quote!(
+            ::core::iter::Iterator::find(&mut self.#df.values(), #find).map(|cell| &*cell.value)
+        );
+        dyn_get_mut = quote!(
+            ::core::iter::Iterator::find(&mut self.#df.values_mut(), #find).map(|cell| &mut *cell.value)
         );
+        dyn_names =
+            quote!(::core::iter::Iterator::filter_map(self.#df.values(), |cell| cell.type_name));
     }
 
-    // Implement default, for convenience.
-    impls.push(quote!(
-        impl core::default::Default for #name {
-            fn default() -> Self { Self::new() }
+    let find_key = quote!(
+        let key = #key_enum::from_name(name).or_else(|| {
+            ::core::iter::Iterator::find(&mut ::core::iter::IntoIterator::into_iter(#key_enum::ALL), |key| {
+                key.type_name() == name
+            })
+        });
+    );
+
+    quote!(
+        /// Get a value by name.
+        ///
+        /// Fixed entries are found by the name of their field, then by the name of their key type as given by
+        /// `core::any::type_name`.  Values in the dynamic section are found by the name of their key type, except for
+        /// those inserted with `insert_boxed`, whose type isn't known by name.  Since `type_name` is only meant for
+        /// diagnostics, its output can change between compiler versions, so it shouldn't be stored.
+        #vis fn get_by_name(&self, name: &str) -> ::core::option::Option<&dyn ::core::any::Any> {
+            #find_key
+            if let ::core::option::Option::Some(key) = key {
+                return ::core::option::Option::Some(self.get_by_index(key.index()));
+            }
+            #dyn_get
         }
-    ));
 
-    // Now punch out the IterableAs.
-    for (trait_path, method_name) in map.parsed_attrs.iterable_traits.iter() {
-        let const_iter_type = build_iter_type(map, trait_path, false, quote!('a));
-        let mut_iter_type = build_iter_type(map, trait_path, true, quote!('a));
-        let method_name_mut = quote::format_ident!("{}_mut", method_name);
+        /// Get a mutable reference to a value by name, as for `get_by_name`.
+        #vis fn get_by_name_mut(&mut self, name: &str) -> ::core::option::Option<&mut dyn ::core::any::Any> {
+            #find_key
+            if let ::core::option::Option::Some(key) = key {
+                return ::core::option::Option::Some(self.get_by_index_mut(key.index()));
+            }
+            #dyn_get_mut
+        }
+
+        /// The names of every entry that `get_by_name` can find: the fields of fixed entries in declaration order,
+        /// then the type names of values in the dynamic section in no particular order.
+        #vis fn names(&self) -> impl ::core::iter::Iterator<Item = &'static str> + '_ {
+            ::core::iter::Iterator::chain(
+                ::core::iter::Iterator::map(::core::iter::IntoIterator::into_iter(#key_enum::ALL), #key_enum::name),
+                #dyn_names,
+            )
+        }
+    )
+}
+
+/// Build `insert_by_name`, which builds a value with a `TypeRegistry` and inserts it.
+fn build_registry_insert(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+
+    quote!(
+        /// Build the type registered under `name` from `args`, and insert it.
+        ///
+        /// This is for scripts and save files, which know types by name.  Returns the old value if there was one, as
+        /// from `insert_boxed`, and fails if nothing is registered under the name or the value can't be inserted.
+        #vis fn insert_by_name<Args>(
+            &mut self,
+            registry: &#krate::TypeRegistry<Self, Args>,
+            name: &str,
+            args: Args,
+        ) -> ::core::result::Result<::core::option::Option<::std::boxed::Box<dyn ::core::any::Any>>, #krate::RegistryError> {
+            registry.insert(self, name, args)
+        }
+    )
+}
+
+/// Build `save` and `load`, if the map asked for them.
+fn build_persistence(map: &Map) -> TokenStream2 {
+    let version = match &map.parsed_attrs.persist {
+        Some(p) => p.version,
+        None => return quote!(),
+    };
+
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let mut encodes = vec![];
+    let mut decodes = vec![];
+    let mut decoded_decls = vec![];
+    let mut assignments = vec![];
+
+    // Keys from `StableKey` are only known once the compiler has them, so duplicates are caught in const evaluation.
+    // Otherwise, the later entry would never be loaded.
+    let persisted = map
+        .entries
+        .iter()
+        .filter(|e| e.is_persisted(map))
+        .collect::<Vec<_>>();
+    let mut key_checks = vec![];
+    for (i, a) in persisted.iter().enumerate() {
+        for b in persisted[i + 1..].iter() {
+            let (key_a, key_b) = (a.stable_key(map), b.stable_key(map));
+            let (cfgs_a, cfgs_b) = (&a.cfgs, &b.cfgs);
+            let (type_a, type_b) = (&a.key_type, &b.key_type);
+            let msg = format!(
+                "`{}` and `{}` are saved under the same key; give one of them another with `#[fixed_typemap(key = \"...\")]`",
+                quote!(#type_a).to_string().replace(' ', ""),
+                quote!(#type_b).to_string().replace(' ', ""),
+            );
+            key_checks.push(quote!(
+                #(#cfgs_a)*
+                #(#cfgs_b)*
+                const _: () = ::core::assert!(!#krate::stable_keys_equal(#key_a, #key_b), #msg);
+            ));
+        }
+    }
+
+    for e in persisted {
+        let cfgs = &e.cfgs;
+        let key = e.stable_key(map);
+        let value_type = e.value_type();
+        let borrow = e.borrow(map, quote!(self), false);
+        let store = e.store(quote!(self), quote!(value));
+        let decoded = quote::format_ident!("decoded_{}", e.name.as_ref().unwrap());
+
+        encodes.push(quote!(
+            #(#cfgs)*
+            {
+                let mut bytes = ::std::vec::Vec::new();
+                #krate::Codec::encode(#borrow, &mut bytes);
+                entries.push((#key, bytes));
+            }
+        ));
+
+        let decode = match &e.parsed_attrs.migrate {
+            Some(migrate) => quote!(if saved.version == #version {
+                #krate::Codec::decode_all(bytes)
+            } else {
+                #migrate(saved.version, bytes)
+            }),
+            None => quote!(#krate::Codec::decode_all(bytes)),
+        };
+        decoded_decls.push(quote!(
+            #(#cfgs)*
+            let mut #decoded: ::core::option::Option<#value_type> = ::core::option::Option::None;
+        ));
+        decodes.push(quote!(
+            #(#cfgs)*
+            if key == #key {
+                let value: ::core::result::Result<#value_type, #krate::CodecError> = #decode;
+                #decoded = ::core::option::Option::Some(value.map_err(|e| #krate::PersistError::decode(key, e))?);
+                continue;
+            }
+        ));
+        assignments.push(quote!(
+            #(#cfgs)*
+            if let ::core::option::Option::Some(value) = #decoded {
+                #store;
+            }
+        ));
+    }
+
+    quote!(
+        /// Write every persisted entry to `writer`, keyed by its stable key, along with the version of the map.
+        ///
+        /// Values in the dynamic section aren't saved.
+        #vis fn save(&self, writer: &mut impl ::std::io::Write) -> ::core::result::Result<(), #krate::PersistError> {
+            let mut entries: ::std::vec::Vec<(&str, ::std::vec::Vec<u8>)> = ::std::vec::Vec::new();
+            #(#encodes)*
+            #krate::write_saved(writer, #version, &entries)
+        }
+
+        /// Read data written by `save` and set the entries in it.
+        ///
+        /// Entries missing from the data keep their values, and keys which are no longer in the map are ignored.  Data
+        /// from older versions of the map goes through the entries' migration functions, and data from newer versions
+        /// is an error.  If anything fails to decode, the map is left unchanged.
+        #vis fn load(&mut self, reader: &mut impl ::std::io::Read) -> ::core::result::Result<(), #krate::PersistError> {
+            #(#key_checks)*
+            let saved = #krate::read_saved(reader, #version)?;
+            #(#decoded_decls)*
+            for (key, bytes) in saved.entries.iter() {
+                // Unused if nothing is persisted.
+                #[allow(unused_variables)]
+                let key = key.as_str();
+                #[allow(unused_variables)]
+                let bytes = bytes.as_slice();
+                #(#decodes)*
+            }
+            #(#assignments)*
+            ::core::result::Result::Ok(())
+        }
+    )
+}
+
+/// The value types of the entries, for bounding visitors, and the aliases needed to name them.
+///
+/// Attributes aren't allowed in where clauses, so entries behind `cfg` are bounded through an alias which is `()` when
+/// the entry is configured out.
+fn visited_types(map: &Map) -> (TokenStream2, Vec<TokenStream2>) {
+    let mut aliases = vec![];
+    let mut types = vec![];
+
+    for (i, e) in map.entries.iter().enumerate() {
+        let value_type = e.value_type();
+        if e.cfgs.is_empty() {
+            types.push(quote!(#value_type));
+            continue;
+        }
+
+        let alias = quote::format_ident!("__{}Visited{}", map.name, i);
+        let cfgs = &e.cfgs;
+        let predicate = cfg_predicate(cfgs);
+        aliases.push(quote!(
+            #(#cfgs)*
+            #[doc(hidden)]
+            #[allow(non_camel_case_types)]
+            type #alias = #value_type;
+            #[cfg(not(#predicate))]
+            #[doc(hidden)]
+            #[allow(non_camel_case_types)]
+            type #alias = ();
+        ));
+        types.push(quote!(#alias));
+    }
 
-        impls.push(quote!(
-            impl<'a> fixed_typemap_internals::IterableAs<'a, #name> for dyn #trait_path + 'a {
-                type Iter = #const_iter_type;
-                type IterMut = #mut_iter_type;
+    (quote!(#(#aliases)*), types)
+}
 
-                fn iter_as(map: &'a #name) -> Self::Iter {
-                    map.#method_name()
-                }
+/// Build `visit` and `visit_mut`, which hand every fixed entry to a visitor without going through trait objects.
+fn build_visit(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let (_, types) = visited_types(map);
+    let mut visits = vec![];
+    let mut visits_mut = vec![];
 
-                fn iter_mut_as(map: &'a mut #name) -> Self::IterMut {
-                    map.#method_name_mut()
-                }
-            }
+    for e in map.entries.iter() {
+        let cfgs = &e.cfgs;
+        let key = &e.key_type;
+        let value_type = e.value_type();
+        let borrow = e.borrow(map, quote!(self), false);
+        let borrow_mut = e.borrow(map, quote!(self), true);
+        visits.push(quote!(
+            #(#cfgs)*
+            #krate::TypemapVisitor::<#value_type>::visit::<#key>(visitor, #borrow);
+        ));
+        visits_mut.push(quote!(
+            #(#cfgs)*
+            #krate::TypemapVisitorMut::<#value_type>::visit_mut::<#key>(visitor, #borrow_mut);
         ));
     }
 
-    quote!(#(#impls)*)
+    quote!(
+        /// Hand every fixed entry to the visitor, in declaration order.
+        ///
+        /// The visitor must be able to visit the value type of every entry, which it usually does with a generic
+        /// implementation bounded by whatever it needs, e.g. `impl<T: Hash> TypemapVisitor<T> for Hasher`.  Lazy entries
+        /// are built first.  The dynamic section isn't visited, since its types aren't known.
+        #vis fn visit<V>(&self, visitor: &mut V)
+        where
+            V: ?::core::marker::Sized #(+ #krate::TypemapVisitor<#types>)*
+        {
+            #(#visits)*
+        }
+
+        /// Hand a mutable reference to every fixed entry to the visitor, in declaration order.
+        #vis fn visit_mut<V>(&mut self, visitor: &mut V)
+        where
+            V: ?::core::marker::Sized #(+ #krate::TypemapVisitorMut<#types>)*
+        {
+            #(#visits_mut)*
+        }
+    )
 }
 
-fn build_constructors(map: &Map) -> TokenStream2 {
-    let mut joined_fields = vec![];
+/// Build `zip_visit` and `zip_visit_mut`, which visit the matching entries of two maps together.
+fn build_zip_visit(map: &Map) -> TokenStream2 {
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let (_, types) = visited_types(map);
+    let mut visits = vec![];
+    let mut visits_mut = vec![];
 
     for e in map.entries.iter() {
-        let name = e.name.as_ref().unwrap();
-        let initializer = &e.initializer;
-        joined_fields.push(quote!(#name: #initializer));
+        let cfgs = &e.cfgs;
+        let key = &e.key_type;
+        let value_type = e.value_type();
+        let left = e.borrow(map, quote!(self), false);
+        let right = e.borrow(map, quote!(other), false);
+        let left_mut = e.borrow(map, quote!(self), true);
+        let right_mut = e.borrow(map, quote!(other), true);
+        visits.push(quote!(
+            #(#cfgs)*
+            #krate::TypemapZipVisitor::<#value_type>::visit::<#key>(visitor, #left, #right);
+        ));
+        visits_mut.push(quote!(
+            #(#cfgs)*
+            #krate::TypemapZipVisitorMut::<#value_type>::visit_mut::<#key>(visitor, #left_mut, #right_mut);
+        ));
     }
 
+    let mut dyn_bound = quote!();
+    let mut dyn_bound_mut = quote!();
+    let mut dyn_visit = quote!();
+    let mut dyn_visit_mut = quote!();
     if map.parsed_attrs.dynamic {
-        let dn = &map.dynamic_field_name;
-        joined_fields.push(quote!(#dn: Default::default()));
+        let df = &map.dynamic_field_name;
+        dyn_bound = quote!(+ #krate::DynamicZipVisitor);
+        dyn_bound_mut = quote!(+ #krate::DynamicZipVisitorMut);
+        dyn_visit = quote!(
+            for (id, cell) in self.#df.iter() {
+                let entry = match other.#df.get(id) {
+                    ::core::option::Option::Some(right) => #krate::Zipped::Both(&*cell.value, &*right.value),
+                    ::core::option::Option::None => #krate::Zipped::LeftOnly(&*cell.value),
+                };
+                #krate::DynamicZipVisitor::visit_dynamic(visitor, *id, entry);
+            }
+            for (id, cell) in other.#df.iter() {
+                if !self.#df.contains_key(id) {
+                    #krate::DynamicZipVisitor::visit_dynamic(visitor, *id, #krate::Zipped::RightOnly(&*cell.value));
+                }
+            }
+        );
+        dyn_visit_mut = quote!(
+            for (id, cell) in self.#df.iter_mut() {
+                let entry = match other.#df.get_mut(id) {
+                    ::core::option::Option::Some(right) => #krate::Zipped::Both(&mut *cell.value, &mut *right.value),
+                    ::core::option::Option::None => #krate::Zipped::LeftOnly(&mut *cell.value),
+                };
+                #krate::DynamicZipVisitorMut::visit_dynamic_mut(visitor, *id, entry);
+            }
+            for (id, cell) in other.#df.iter_mut() {
+                if !self.#df.contains_key(id) {
+                    #krate::DynamicZipVisitorMut::visit_dynamic_mut(visitor, *id, #krate::Zipped::RightOnly(&mut *cell.value));
+                }
+            }
+        );
     }
 
     quote!(
-        /// Construct a new typemap.
+        /// Hand the matching fixed entries of this map and `other` to the visitor together, in declaration order.
         ///
-        /// All fixed fields will have their specified default value.  If there is a dynamic sectionb, it will be empty.
-        pub fn new() -> Self {
-            Self {
-                #(#joined_fields),*
-            }
+        /// This is for comparing or interpolating between two instances of a map.  As with `visit`, the visitor is
+        /// called with the concrete type of each entry.  If the map is dynamic, the visitor then gets each type in
+        /// either dynamic section as a `Zipped` value, saying which maps have it, in no particular order.
+        #vis fn zip_visit<V>(&self, other: &Self, visitor: &mut V)
+        where
+            V: ?::core::marker::Sized #(+ #krate::TypemapZipVisitor<#types>)* #dyn_bound
+        {
+            #(#visits)*
+            #dyn_visit
+        }
+
+        /// Like `zip_visit`, with mutable references to the entries of both maps.
+        #vis fn zip_visit_mut<V>(&mut self, other: &mut Self, visitor: &mut V)
+        where
+            V: ?::core::marker::Sized #(+ #krate::TypemapZipVisitorMut<#types>)* #dyn_bound_mut
+        {
+            #(#visits_mut)*
+            #dyn_visit_mut
         }
     )
 }
 
-/// Build the low-level unsafe get methods.
-fn build_unsafe_getters(map: &Map) -> TokenStream2 {
-    let mut type_field = vec![];
-    for e in map.entries.iter() {
-        type_field.push((&e.key_type, e.name.as_ref().unwrap()));
+/// Build the type returned by `diff`, which has an optional new value for each fixed entry and a list of changes to the
+/// dynamic section.
+fn build_diff_type(map: &Map) -> TokenStream2 {
+    if !map.parsed_attrs.diff {
+        return quote!();
     }
 
-    let mut funcs = vec![];
-
-    for (fname, is_mut) in [("get_const_ptr", false), ("get_mut_ptr", true)] {
-        let const_or_mut = if is_mut { quote!(mut) } else { quote!(const) };
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let name = &map.diff_type_name;
+    let key_enum = &map.key_enum_name;
+    let (_, types) = visited_types(map);
+    let mut fields = vec![];
+    let mut empty_fields = vec![];
+    let mut changed_arms = vec![];
+    let mut is_empty = vec![];
+    let mut get_clauses = vec![];
+    let mut visits = vec![];
 
-        let maybe_mut = if is_mut { quote!(mut) } else { quote!() };
+    for e in map.entries.iter() {
+        let cfgs = &e.cfgs;
+        let field = e.name.as_ref().unwrap();
+        let key = &e.key_type;
+        let value_type = e.value_type();
+        let variant = e.variant();
+        fields.push(quote!(#(#cfgs)* #field: ::core::option::Option<#value_type>));
+        empty_fields.push(quote!(#(#cfgs)* #field: ::core::option::Option::None));
+        changed_arms.push(quote!(#(#cfgs)* #key_enum::#variant => self.#field.is_some()));
+        is_empty.push(quote!(#(#cfgs)* { empty &= self.#field.is_none(); }));
+        get_clauses.push(quote!(
+            #(#cfgs)*
+            if ::core::any::TypeId::of::<K>() == ::core::any::TypeId::of::<#key>() {
+                return self.#field.as_ref().and_then(|x| (x as &dyn ::core::any::Any).downcast_ref::<V>());
+            }
+        ));
+        visits.push(quote!(
+            #(#cfgs)*
+            if let ::core::option::Option::Some(value) = &self.#field {
+                #krate::TypemapVisitor::<#value_type>::visit::<#key>(visitor, value);
+            }
+        ));
+    }
 
-        let fident = quote::format_ident!("{}", fname);
-        let clauses = type_field
-            .iter()
-            .map(|(key, field)| {
-                quote!(if core::any::TypeId::of::<K>() == core::any::TypeId::of::<#key>() {
-                    return Some(&#maybe_mut self.#field
-                        as *#const_or_mut #key as *#const_or_mut u8);
-                })
+    let mut dynamic_methods = quote!();
+    let mut dynamic_get = quote!(::core::option::Option::None);
+    if map.parsed_attrs.dynamic {
+        let df = &map.dynamic_field_name;
+        let cell = &map.dynamic_cell_name;
+        fields.push(
+            quote!(#df: ::std::vec::Vec<(::core::any::TypeId, #krate::DynamicChange<#cell>)>),
+        );
+        empty_fields.push(quote!(#df: ::std::vec::Vec::new()));
+        is_empty.push(quote!(empty &= self.#df.is_empty();));
+        dynamic_get = quote!(
+            ::core::iter::Iterator::find(&mut ::core::iter::IntoIterator::into_iter(&self.#df), |(id, _)| {
+                *id == ::core::any::TypeId::of::<K>()
             })
-            .collect::<Vec<_>>();
+            .and_then(|(_, change)| match change {
+                #krate::DynamicChange::Inserted(cell) | #krate::DynamicChange::Changed(cell) => (*cell.value).downcast_ref::<V>(),
+                #krate::DynamicChange::Removed => ::core::option::Option::None,
+            })
+        );
+        dynamic_methods = quote!(
+            /// The changes to the dynamic section, by key, in no particular order, with the new values.
+            #vis fn dynamic_changes(&self) -> impl ::core::iter::Iterator<Item = (::core::any::TypeId, #krate::DynamicChange<&dyn ::core::any::Any>)> + '_ {
+                ::core::iter::Iterator::map(self.#df.iter(), |(id, change)| {
+                    (*id, change.as_ref().map(|cell| &*cell.value))
+                })
+            }
+        );
+    }
 
-        let mut final_clause = quote!(None);
-        if map.parsed_attrs.dynamic {
-            let suffix = if is_mut { "_mut" } else { "" };
-            let map_getter = quote::format_ident!("get{}", suffix);
-            let any_ref = quote::format_ident!("downcast_{}", if is_mut { "mut" } else { "ref" });
-            let df = &map.dynamic_field_name;
+    let codec = build_diff_codec(map, &empty_fields);
 
-            // Since this is a map keyed by type id, if we find the type id we expect then we found something of that
-            // type.  Thus an unsafe/fast unwrap is safe.
-            let downcaster = fast_unwrap(quote!((&#maybe_mut *x.value).#any_ref::<K>()));
+    let doc = format!(
+        "The changes which turn one [`{}`] into another, from `diff`, to be replayed with `apply`.",
+        map.name
+    );
 
-            final_clause = quote!({
-                self.#df.#map_getter(&core::any::TypeId::of::<K>()).map(|x| {
-                    (#downcaster) as *#const_or_mut K as *#const_or_mut u8
-                })
-            });
+    quote!(
+        #[doc = #doc]
+        #vis struct #name {
+            #(#fields),*
         }
 
-        funcs.push(quote!(
-            fn #fident<K: core::any::Any>(&#maybe_mut self) -> Option<*#const_or_mut u8> {
-                use core::any::Any;
+        impl #name {
+            /// Whether the maps were the same.
+            #vis fn is_empty(&self) -> bool {
+                let mut empty = true;
+                #(#is_empty)*
+                empty
+            }
 
-                #(#clauses)*
+            /// The fixed entries which changed, in declaration order.
+            #vis fn changed(&self) -> impl ::core::iter::Iterator<Item = #key_enum> + '_ {
+                ::core::iter::Iterator::filter(::core::iter::IntoIterator::into_iter(#key_enum::ALL), move |key| match key {
+                    #(#changed_arms),*
+                })
+            }
 
-                #final_clause
+            /// Get the new value of type `V` stored under the key `K`, if the diff changes it.
+            fn get_value<K: ::core::any::Any, V: ::core::any::Any>(&self) -> ::core::option::Option<&V> {
+                #(#get_clauses)*
+                #dynamic_get
             }
-        ));
-    }
 
-    quote!(#(#funcs)*)
-}
+            /// The new value of `K`, if it was changed or inserted.
+            #vis fn get<K: ::core::any::Any>(&self) -> ::core::option::Option<&K> {
+                self.get_value::<K, K>()
+            }
 
-fn build_infallible_getters(map: &Map) -> TokenStream2 {
-    let mn = &map.name;
-    let additional_constraints = &map.additional_key_constraints;
-    quote!(
-        /// Get a value from the typemap which is guaranteed to be present.
-        ///
-        /// Your program won't compile if it's not.  Compiles down to a simple field borrow.
-        #[inline(always)]
-        pub fn get_infallible<K: fixed_typemap_internals::InfallibleKey<#mn> + #(#additional_constraints)+*>(&self) -> &K {
-            K::get_infallible(self)
-        }
+            /// The new value stored for a `TypemapKey`, if it was changed or inserted.
+            #vis fn get_keyed<K: #krate::TypemapKey>(&self) -> ::core::option::Option<&K::Value> {
+                self.get_value::<K, K::Value>()
+            }
 
-        /// get a mutable reference to a type guaranteed to be in the typemap.
-        ///
-        /// If it's not, your program won't compile.
-        #[inline(always)]
-        pub fn get_infallible_mut<K: fixed_typemap_internals::InfallibleKey<#mn> + #(#additional_constraints)+*>(&mut self) -> &mut K {
-            K::get_infallible_mut(self)
+            /// Hand the new value of every changed fixed entry to the visitor, in declaration order.
+            ///
+            /// Like the map's `visit`, this calls the visitor with the concrete type of each entry, e.g. to serialize
+            /// the changes.
+            #vis fn visit<V>(&self, visitor: &mut V)
+            where
+                V: ?::core::marker::Sized #(+ #krate::TypemapVisitor<#types>)*
+            {
+                #(#visits)*
+            }
+
+            #dynamic_methods
         }
+
+        #codec
     )
 }
 
-fn build_fallible_getters(map: &Map) -> TokenStream2 {
-    let additional_constraints = &map.additional_key_constraints;
+/// For maps which are persisted as well as diffed, implement `Codec` for the diff so that it can be sent elsewhere.
+///
+/// Changed entries are written under their stable keys, as `save` does.  The dynamic section can't be encoded, since
+/// there would be no way to know what to decode its values as.
+fn build_diff_codec(map: &Map, empty_fields: &[TokenStream2]) -> TokenStream2 {
+    if map.parsed_attrs.persist.is_none() {
+        return quote!();
+    }
+
+    let krate = &map.crate_path;
+    let name = &map.diff_type_name;
+    let mut encodes = vec![];
+    let mut decodes = vec![];
+
+    for e in map.entries.iter().filter(|e| e.is_persisted(map)) {
+        let cfgs = &e.cfgs;
+        let field = e.name.as_ref().unwrap();
+        let key = e.stable_key(map);
+        encodes.push(quote!(
+            #(#cfgs)*
+            if let ::core::option::Option::Some(value) = &self.#field {
+                let mut bytes = ::std::vec::Vec::new();
+                #krate::Codec::encode(value, &mut bytes);
+                entries.push((#key, bytes));
+            }
+        ));
+        decodes.push(quote!(
+            #(#cfgs)*
+            if key == #key {
+                let value = #krate::Codec::decode_all(&bytes)
+                    .map_err(|e| #krate::CodecError::new(::std::format!("`{}`: {}", key, e)))?;
+                diff.#field = ::core::option::Option::Some(value);
+                continue;
+            }
+        ));
+    }
+
     quote!(
-        /// Try to get a type from the typemap.
-        #[inline(always)]
-        pub fn get<K: core::any::Any + #(#additional_constraints)+*>(&self) -> Option<&K> {
-            self.get_const_ptr::<K>()
-                .map(|x| unsafe { &*(x as *const K) })
-        }
+        /// Encodes the changed fixed entries under their stable keys.  Entries which aren't persisted and changes to
+        /// the dynamic section are left out, and keys which the map doesn't have are skipped when decoding.
+        impl #krate::Codec for #name {
+            fn encode(&self, out: &mut ::std::vec::Vec<u8>) {
+                let mut entries: ::std::vec::Vec<(&str, ::std::vec::Vec<u8>)> = ::std::vec::Vec::new();
+                #(#encodes)*
+                #krate::Codec::encode(&entries.len(), out);
+                for (key, bytes) in entries {
+                    #krate::Codec::encode(&::std::string::ToString::to_string(key), out);
+                    #krate::Codec::encode(&bytes, out);
+                }
+            }
 
-        /// Try to get a mutable reference to a value in the typemap.
-        #[inline(always)]
-        pub fn get_mut<K: core::any::Any + #(#additional_constraints)+*>(&mut self) -> Option<&mut K> {
-            self.get_mut_ptr::<K>()
-                .map(|x| unsafe { &mut *(x as *mut K) })
+            fn decode(input: &mut &[u8]) -> ::core::result::Result<Self, #krate::CodecError> {
+                #[allow(unused_mut)]
+                let mut diff = #name {
+                    #(#empty_fields),*
+                };
+                let count = <usize as #krate::Codec>::decode(input)?;
+                for _ in 0..count {
+                    let key = <::std::string::String as #krate::Codec>::decode(input)?;
+                    let bytes = <::std::vec::Vec<u8> as #krate::Codec>::decode(input)?;
+                    // Unused if nothing is persisted.
+                    #[allow(unused_variables)]
+                    let key = key.as_str();
+                    #(#decodes)*
+                }
+                ::core::result::Result::Ok(diff)
+            }
         }
     )
 }
 
-fn build_insert(map: &Map) -> TokenStream2 {
-    let additional_constraints = &map.additional_key_constraints;
+/// Build `diff` and `apply`, if the map asked for them.
+fn build_diff(map: &Map) -> TokenStream2 {
+    if !map.parsed_attrs.diff {
+        return quote!();
+    }
+
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let name = &map.diff_type_name;
+    let mut fields = vec![];
+    let mut applies = vec![];
+
+    for e in map.entries.iter() {
+        let cfgs = &e.cfgs;
+        let field = e.name.as_ref().unwrap();
+        let left = e.borrow(map, quote!(self), false);
+        let right = e.borrow(map, quote!(other), false);
+        let store = e.store(quote!(self), quote!(value));
+        fields.push(quote!(
+            #(#cfgs)*
+            #field: {
+                let (left, right) = (#left, #right);
+                if ::core::cmp::PartialEq::ne(left, right) {
+                    ::core::option::Option::Some(::core::clone::Clone::clone(right))
+                } else {
+                    ::core::option::Option::None
+                }
+            }
+        ));
+        applies.push(quote!(
+            #(#cfgs)*
+            if let ::core::option::Option::Some(value) = diff.#field {
+                #store;
+            }
+        ));
+    }
 
-    let mut dynamic_clause = quote!(Err(()));
+    let mut dyn_diff = quote!();
+    let mut dyn_apply = quote!();
     if map.parsed_attrs.dynamic {
         let df = &map.dynamic_field_name;
-        let dc = &map.dynamic_cell_name;
-        let unwrapper = fast_unwrap(quote!(x.value.downcast::<K>().ok()));
-        dynamic_clause = quote!(
-            let tid = core::any::TypeId::of::<K>();
-            Ok(self.#df.insert(tid, #dc::new(value))
-                .map(|x| *(#unwrapper)))
+        fields.push(quote!(#df: changes));
+        dyn_diff = quote!(
+            let mut changes = ::std::vec::Vec::new();
+            for (id, right) in other.#df.iter() {
+                match self.#df.get(id) {
+                    ::core::option::Option::None => {
+                        changes.push((*id, #krate::DynamicChange::Inserted(right.clone_cell())));
+                    }
+                    ::core::option::Option::Some(left) => {
+                        if !(left.diff_eq)(&*left.value, &*right.value) {
+                            changes.push((*id, #krate::DynamicChange::Changed(right.clone_cell())));
+                        }
+                    }
+                }
+            }
+            for id in self.#df.keys() {
+                if !other.#df.contains_key(id) {
+                    changes.push((*id, #krate::DynamicChange::Removed));
+                }
+            }
+        );
+        dyn_apply = quote!(
+            for (id, change) in diff.#df {
+                match change {
+                    #krate::DynamicChange::Inserted(cell) | #krate::DynamicChange::Changed(cell) => {
+                        self.#df.insert(id, cell);
+                    }
+                    #krate::DynamicChange::Removed => {
+                        self.#df.remove(&id);
+                    }
+                }
+            }
         );
     }
 
     quote!(
-        /// Try to insert into the typemap.
+        /// Work out what changed between this map and `other`, so that `apply` can turn a copy of this map into
+        /// `other`.
         ///
-        /// Like the std collections, inserting a value that's already in the map returns `Some(old_value)` and updates
-        /// it. Errors if the typemap is fixed and the type provided isn't present.
-        pub fn insert<K: core::any::Any + #(#additional_constraints)+*>(&mut self, mut value: K) -> Result<Option<K>, ()> {
-            use core::any::Any;
-
-            if let Some(x) = self.get_mut_ptr::<K>() {
-                core::mem::swap(&mut value, unsafe { &mut *(x as *mut K) });
-                return Ok(Some(value));
+        /// Fixed entries which differ by `PartialEq` are cloned from `other`, as are values in its dynamic section which
+        /// are new or differ.  Values only in this map's dynamic section are removed.
+        #vis fn diff(&self, other: &Self) -> #name {
+            #dyn_diff
+            #name {
+                #(#fields),*
             }
-
-            #dynamic_clause
         }
 
-        /// Insert into the typemap where the key is known to be in the typemap at the type system level.
-        pub fn insert_infallible<K: fixed_typemap_internals::InfallibleKey<Self>>(&mut self, value: K) -> Option<K> {
-            K::insert_infallible(self, value)
+        /// Replay a diff from `diff`.
+        #vis fn apply(&mut self, diff: #name) {
+            #(#applies)*
+            #dyn_apply
         }
     )
 }
 
-fn build_iterators(map: &Map) -> TokenStream2 {
-    let mut methods = vec![];
-
-    for (trait_path, name) in map.parsed_attrs.iterable_traits.iter() {
-        for is_mut in [false, true] {
-            let method_name = quote::format_ident!("{}{}", name, if is_mut { "_mut" } else { "" });
-            let cell_type = &map.dynamic_cell_name;
-            let maybe_mut = if is_mut { quote!(mut) } else { quote!() };
-            let iter_fn = quote::format_ident!("values{}", if is_mut { "_mut" } else { "" });
-            let return_type = build_iter_type(map, &trait_path, is_mut, quote!('_));
-
-            // This works by having two iterators that we chain.  The first is a fixed-sized array which consists of the
-            // non-dynamic fields pre-cast to the trait object.  The second consists of a map over the cell type, using
-            // the inline function pointers therein to convert to the trait object as needed.
-            let static_fields = map
-                .entries
-                .iter()
-                .map(|e| {
-                    let fname = &e.name.as_ref().unwrap();
-
-                    quote!(&#maybe_mut self.#fname as &#maybe_mut dyn #trait_path)
-                })
-                .collect::<Vec<_>>();
-            let static_fields_len = static_fields.len();
+/// Build `apply_config`, if the map asked for it.
+fn build_config(map: &Map) -> TokenStream2 {
+    if !map.parsed_attrs.config {
+        return quote!();
+    }
 
-            let mut dynamic_part = quote!(let dyn_iter = core::iter::empty());
-            if map.parsed_attrs.dynamic {
-                let df = &map.dynamic_field_name;
-                dynamic_part = quote!(
-                    let dyn_ref = &#maybe_mut self.#df;
-                    // We need a function which can be used as a function pointer to convert, as well as the map.  This
-                    // makes it possible to name the return type.
-                    fn conv<'b>(cell: &'b #maybe_mut #cell_type) -> &'b #maybe_mut dyn #trait_path {
-                        (cell.#method_name)(&#maybe_mut *cell.value)
-                    }
+    let vis = &map.vis;
+    let krate = &map.crate_path;
+    let mut arms = vec![];
 
-                    let dyn_iter = dyn_ref.#iter_fn().map(conv as fn(&#maybe_mut #cell_type) -> &#maybe_mut dyn #trait_path);
-                )
+    for e in map.entries.iter() {
+        let key = match e.config_key() {
+            Some(k) => k,
+            None => continue,
+        };
+        let value_type = e.value_type();
+        let store = e.store(quote!(self), quote!(parsed));
+        let cfgs = &e.cfgs;
+        arms.push(quote!(
+            #(#cfgs)*
+            #key => match <#value_type as ::core::str::FromStr>::from_str(value) {
+                ::core::result::Result::Ok(parsed) => {
+                    #store;
+                }
+                ::core::result::Result::Err(e) => errors.push(#krate::ConfigError::parse(key, value, &e)),
             }
+        ));
+    }
 
-            methods.push(quote!(
-                pub fn #method_name(&#maybe_mut self) -> #return_type {
-                    let static_arr: [&#maybe_mut dyn #trait_path; #static_fields_len] = [#(#static_fields),*];
-                    let static_iter = static_arr.into_iter();
-                    #dynamic_part
-                    static_iter.chain(dyn_iter)
+    quote!(
+        /// Set entries from `(key, value)` pairs, such as the lines of a settings file, by parsing each value with
+        /// `FromStr`.
+        ///
+        /// Every pair is tried, and those which parse are applied even if others fail.  Keys which don't name an entry
+        /// and values which don't parse are all reported together.
+        #vis fn apply_config<'a>(
+            &mut self,
+            config: impl ::core::iter::IntoIterator<Item = (&'a str, &'a str)>,
+        ) -> ::core::result::Result<(), #krate::ConfigErrors> {
+            let mut errors = <#krate::ConfigErrors as ::core::default::Default>::default();
+            for (key, value) in config {
+                match key {
+                    #(#arms)*
+                    _ => errors.push(#krate::ConfigError::unknown_key(key)),
                 }
-            ));
+            }
+            errors.into_result()
         }
+    )
+}
+
+/// Build the private functions which run the initializers of lazy entries.
+fn build_lazy_initializers(map: &Map) -> TokenStream2 {
+    let mut funcs = vec![];
+
+    for e in map.entries.iter().filter(|e| e.is_lazy()) {
+        let fname = e.lazy_init_fn();
+        let storage_type = e.value_storage_type();
+        let initializer = &e.initializer;
+        let wrapped = e.wrap_value(quote!(#initializer));
+        let cfgs = &e.cfgs;
+        funcs.push(quote!(
+            #(#cfgs)*
+            fn #fname() -> #storage_type {
+                #wrapped
+            }
+        ));
     }
 
-    quote!(#(#methods)*)
+    quote!(#(#funcs)*)
 }
 
 fn build_impl_block(map: &Map) -> TokenStream2 {
     let mn = &map.name;
     let constructors = build_constructors(map);
+    let lazy_initializers = build_lazy_initializers(map);
     let unsafe_getters = build_unsafe_getters(map);
     let infallible_getters = build_infallible_getters(map);
     let fallible_getters = build_fallible_getters(map);
     let insert = build_insert(map);
-    let iterators = build_iterators(&map);
+    let boxed_access = build_boxed_access(map);
+    let len = build_len(map);
+    let iterators = build_iterators(map);
+    let trait_getters = build_trait_getters(map);
+    let accessors = build_accessors(map);
+    let index_access = build_index_access(map);
+    let name_access = build_name_access(map);
+    let config = build_config(map);
+    let registry_insert = build_registry_insert(map);
+    let persistence = build_persistence(map);
+    let visit = build_visit(map);
+    let zip_visit = build_zip_visit(map);
+    let diff = build_diff(map);
 
     quote!(impl #mn {
         #constructors
+        #lazy_initializers
         #unsafe_getters
         #infallible_getters
         #fallible_getters
         #insert
+        #boxed_access
+        #len
         #iterators
+        #trait_getters
+        #accessors
+        #index_access
+        #name_access
+        #config
+        #registry_insert
+        #persistence
+        #visit
+        #zip_visit
+        #diff
     })
 }
 
+/// Check the map, then build everything but the struct itself.
+fn build_map(map: &Map) -> TokenStream2 {
+    // Invalid attributes leave the defaults behind, which can't collide, so these can be reported together.
+    let attribute_checks = [
+        check_attributes(map),
+        check_method_names(map),
+        check_type_names(map),
+    ];
+    if let Err(e) = combine_errors(attribute_checks.into_iter().filter_map(Result::err))
+        .and_then(|_| check_options(map))
+        .and_then(|_| check_duplicate_keys(map))
+        .and_then(|_| check_variant_names(map))
+        .and_then(|_| check_initializer_references(map))
+    {
+        return e.to_compile_error();
+    }
+
+    let key_traits = build_trait_impls(map);
+    let cell_type = build_cell_type(map);
+    let key_enum = build_key_enum(map);
+    let (visit_aliases, _) = visited_types(map);
+    let diff_type = build_diff_type(map);
+    let impl_block = build_impl_block(map);
+
+    quote!(
+        #key_traits
+        #cell_type
+        #key_enum
+        #visit_aliases
+        #diff_type
+        #impl_block
+    )
+}
+
 /// Generate a fixed typemap.
 #[proc_macro]
 pub fn decl_fixed_typemap(input: TokenStream) -> TokenStream {
     let mut map = syn::parse_macro_input!(input as Map);
     ensure_names(&mut map);
     let struct_def = build_struct(&map);
-    let key_traits = build_trait_impls(&map);
-    let cell_type = build_cell_type(&map);
-    let impl_block = build_impl_block(&map);
+    let rest = build_map(&map);
 
     quote!(#struct_def
-        #key_traits
-        #cell_type
-        #impl_block
+        #rest
+    )
+    .into()
+}
+
+/// Implement `StableKey`, with the key from `#[fixed_typemap(key = "...")]` or the name of the type.
+#[proc_macro_derive(StableKey, attributes(fixed_typemap))]
+pub fn derive_stable_key(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    let (attrs, errors): (StableKeyAttributes, _) =
+        parse_attributes(&input.attrs, input.ident.span());
+    if let Some(e) = errors {
+        return e.to_compile_error().into();
+    }
+
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return syn::Error::new_spanned(
+            &input.generics,
+            "`StableKey` can't be derived for generic types, since every instantiation would have the same key",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    // The bare name would be the same for types with the same name in different modules.
+    let name_str = name.to_string();
+    let key = match attrs.key {
+        Some(k) => quote!(#k),
+        None => quote!(::core::concat!(::core::module_path!(), "::", #name_str)),
+    };
+    let krate = attrs
+        .crate_path
+        .unwrap_or_else(|| parse_quote!(::fixed_typemap));
+    quote!(
+        impl #krate::StableKey for #name {
+            const KEY: &'static str = #key;
+        }
     )
     .into()
 }
+
+/// Turn an ordinary struct into a fixed typemap.
+#[proc_macro_derive(FixedTypemap, attributes(fixed_typemap))]
+pub fn derive_fixed_typemap(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    match map_from_derive(input) {
+        Ok(map) => build_map(&map).into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}